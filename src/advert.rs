@@ -1,13 +1,30 @@
+use std::collections::HashMap;
+use std::fs;
+
 use ab_glyph::PxScale;
-use const_format::formatcp;
-use image::{DynamicImage, ImageFormat, Rgba};
+use chrono::{DateTime, Utc};
+use image::{DynamicImage, GenericImageView, GrayImage, ImageFormat, Luma, Rgba, RgbaImage};
 use image::io::Reader as ImageReader;
 use serde::Deserialize;
 
+use crate::animation::Animation;
+use crate::config::{config_warning, ImageSizeMismatchPolicy, ServerDefinition};
+use crate::iso_string;
+use crate::meter::{Meter, ProgressBar};
+use crate::placeholder::low_quality_placeholder;
+use crate::qr::QrOverlay;
+use crate::text::{dominant_color, missing_glyphs, palette_text_color};
+
 /// simple struct that maps to config file entries
-#[derive(Deserialize)]
+#[derive(Deserialize, PartialEq)]
 pub struct AdvertDefinition {
-    pub image: String,
+    /// if unset, adverts are drawn onto a transparent canvas
+    pub image: Option<String>,
+    /// if set, the image (and any image_overrides) must be in this format, given as a file extension like "png"
+    pub require_source_format: Option<String>,
+    /// if set, rotate the image (and any image_overrides) clockwise by this many degrees when loading it: 90, 180, or
+    /// 270. Everything else about the advert describes the rotated image.
+    pub source_rotation: Option<u32>,
     pub image_width: u32,
     pub image_height: u32,
     /// number of frames, used for animation sprite sheets (currently only vertical stacking is supported)
@@ -15,15 +32,196 @@ pub struct AdvertDefinition {
     pub text_align: Align,
     /// left OR center of text, depending on text_align
     pub text_x: u32,
-    /// top of text
+    /// top of text, or its baseline if text_anchor_baseline is set
     pub text_y: u32,
-    /// RGBA values
-    pub text_color: [u8; 4],
+    /// RGBA values. Required unless palette_text_color is set.
+    pub text_color: Option<[u8; 4]>,
+    /// if text_color isn't set, pick a color that contrasts with the image's dominant color instead
+    #[serde(default)]
+    pub palette_text_color: bool,
     pub text_scale: f32,
     pub text_case: Case,
+    /// replacements made in the location after text_case, in order, e.g. [["1", "١"]] for Eastern Arabic digits
+    #[serde(default)]
+    pub location_substitutions: Vec<[String; 2]>,
     pub output_format: ImageOutput,
-    /// prefix for GeoIP location
+    /// prefix for GeoIP location. `{city}`, `{browser}`, and `{os}` are replaced with what we know about the visitor.
+    /// `file:path` reads it from that file instead.
     pub text_prefix: String,
+    /// round the text baseline to a whole pixel, which is crisper for small text
+    #[serde(default)]
+    pub snap_baseline: bool,
+    /// treat text_y as the baseline of the text rather than its top, like most design tools do
+    #[serde(default)]
+    pub text_anchor_baseline: bool,
+    /// if set, alpha below this becomes fully transparent and everything else fully opaque
+    pub alpha_threshold: Option<u8>,
+    /// ignore text_color, and instead use text_color_dark or text_color_light depending on how bright the image is
+    /// behind the text
+    #[serde(default)]
+    pub auto_contrast: bool,
+    /// RGBA values, used by auto_contrast over bright backgrounds
+    #[serde(default = "default_text_color_dark")]
+    pub text_color_dark: [u8; 4],
+    /// RGBA values, used by auto_contrast over dark backgrounds
+    #[serde(default = "default_text_color_light")]
+    pub text_color_light: [u8; 4],
+    /// how long each frame is shown for, in animated output formats
+    #[serde(default = "default_frame_duration_ms")]
+    pub frame_duration_ms: u32,
+    /// if set, characters bob up and down along a sine wave this many pixels high, which moves from frame to frame
+    pub wave_amplitude: Option<f32>,
+    /// how far along the wave each character is from the last, in radians
+    #[serde(default = "default_wave_frequency")]
+    pub wave_frequency: f32,
+    /// if set, draw a QR code with this content on each frame. `{city}`, `{browser}`, and `{os}` are replaced as in text_prefix.
+    pub qr_content: Option<String>,
+    /// left of the QR code
+    #[serde(default)]
+    pub qr_x: u32,
+    /// top of the QR code
+    #[serde(default)]
+    pub qr_y: u32,
+    /// width and height of the QR code in pixels, required if qr_content is set
+    #[serde(default)]
+    pub qr_size: u32,
+    /// sample characters the font must be able to draw, e.g. the alphabet of the locale this advert targets
+    pub require_glyphs: Option<String>,
+    /// formats to try, in order, if encoding to output_format fails. Defaults to Png alone. If empty, the render fails.
+    #[serde(default = "default_fallback_formats")]
+    pub fallback_formats: Vec<ImageOutput>,
+    /// embed the advert name and location in the output's metadata, for analytics tools that read it
+    #[serde(default)]
+    pub write_metadata: bool,
+    /// if set, draw a meter this many pixels wide on each frame
+    pub meter_width: Option<u32>,
+    /// height of the meter in pixels, required if meter_width is set
+    #[serde(default)]
+    pub meter_height: u32,
+    /// left of the meter
+    #[serde(default)]
+    pub meter_x: u32,
+    /// top of the meter
+    #[serde(default)]
+    pub meter_y: u32,
+    /// how full the meter is, from 0.0 to 1.0. If unset, it's derived from the client's IP.
+    pub meter_fill: Option<f32>,
+    /// RGBA values
+    #[serde(default = "default_meter_fill_color")]
+    pub meter_fill_color: [u8; 4],
+    /// RGBA values
+    #[serde(default = "default_meter_background_color")]
+    pub meter_background_color: [u8; 4],
+    /// RGBA values
+    #[serde(default = "default_meter_border_color")]
+    pub meter_border_color: [u8; 4],
+    /// radius of the meter's corners in pixels, where 0 is square
+    #[serde(default)]
+    pub meter_corner_radius: f32,
+    /// if set, a [x, y, width, height] rectangle of each frame the text must stay out of, e.g. a face in a photo. If
+    /// the text would overlap it, it's moved to just above or just below it instead.
+    pub keep_out_rect: Option<[u32; 4]>,
+    /// if set, draw a progress bar in this [x, y, width, height] rectangle that fills up over the frames
+    pub bar_rect: Option<[u32; 4]>,
+    /// RGBA values
+    #[serde(default = "default_bar_color")]
+    pub bar_color: [u8; 4],
+    /// RGBA values of the unfilled part of the bar. If unset, it's left see-through.
+    pub bar_bg_color: Option<[u8; 4]>,
+    /// if set, sent as the Content-Type instead of the output format's real mime type
+    pub content_type_override: Option<String>,
+    /// other adverts to serve instead to visitors from certain countries, keyed by ISO country code (e.g. "DE")
+    #[serde(default)]
+    pub country_variants: HashMap<String, String>,
+    /// an extra path to serve this advert at, e.g. "/banners/summer/hot.png", alongside /ads/<name>
+    pub path: Option<String>,
+    /// if set, measure the WCAG contrast ratio between the text and its background on every render, and log a
+    /// warning when it's below this (e.g. 4.5)
+    pub min_contrast_ratio: Option<f64>,
+    /// if set, only trust GeoIP to name the visitor's location if it's accurate to within this many kilometers, and use
+    /// the fallback location otherwise
+    pub min_accuracy_km: Option<u32>,
+    /// if set, the output says it's this many dots per inch, so it prints at the intended size. Pixels are unaffected.
+    pub dpi: Option<u16>,
+    /// alternate images for visitors in particular places, keyed by exact city name (as GeoIP gives it) or uppercase
+    /// ISO country code. Cities win over countries.
+    #[serde(default)]
+    pub image_overrides: HashMap<String, String>,
+    /// if more than 0, draw a border this many pixels wide just inside the edges of each frame
+    #[serde(default)]
+    pub border_width: u32,
+    /// RGBA values
+    #[serde(default = "default_border_color")]
+    pub border_color: [u8; 4],
+    /// RGBA values. If set, the border fades from border_color at the top of each frame to this at the bottom.
+    pub border_gradient_color: Option<[u8; 4]>,
+    /// draw the time of the render, small and faint, in the bottom right of each frame, so leaked copies can be traced
+    #[serde(default)]
+    pub watermark: bool,
+    /// add a short hash of the client's IP to the watermark
+    #[serde(default)]
+    pub watermark_ip: bool,
+    /// RGBA values
+    #[serde(default = "default_watermark_color")]
+    pub watermark_color: [u8; 4],
+    /// if set, a grayscale image whose brightness (or alpha, if it has any) becomes the output's alpha, clipping it to
+    /// a shape. Either one frame or the whole sprite sheet in size.
+    pub mask_image: Option<String>,
+    /// RFC 3339 timestamp before which this advert isn't served, e.g. "2024-06-01T00:00:00Z"
+    pub starts_at: Option<String>,
+    /// RFC 3339 timestamp from which this advert is no longer served
+    pub expires_at: Option<String>,
+    /// crop each frame of the output down to just the text, plus autocrop_margin. Composites always use the whole frame.
+    /// Not allowed with frame_maps, and the advert's sprite.json 404s, since the frame size depends on the location.
+    #[serde(default)]
+    pub autocrop_to_text: bool,
+    /// pixels of the image to keep around the text on each side when autocrop_to_text is set
+    #[serde(default)]
+    pub autocrop_margin: u32,
+}
+
+pub fn default_fallback_formats() -> Vec<ImageOutput> {
+    vec![ImageOutput::Png]
+}
+
+pub fn default_frame_duration_ms() -> u32 {
+    100
+}
+
+fn default_wave_frequency() -> f32 {
+    0.5
+}
+
+fn default_text_color_dark() -> [u8; 4] {
+    [0, 0, 0, 255]
+}
+
+fn default_text_color_light() -> [u8; 4] {
+    [255, 255, 255, 255]
+}
+
+fn default_border_color() -> [u8; 4] {
+    [0, 0, 0, 255]
+}
+
+fn default_watermark_color() -> [u8; 4] {
+    [255, 255, 255, 48]
+}
+
+fn default_meter_fill_color() -> [u8; 4] {
+    [40, 200, 60, 255]
+}
+
+fn default_meter_background_color() -> [u8; 4] {
+    [255, 255, 255, 255]
+}
+
+fn default_meter_border_color() -> [u8; 4] {
+    [0, 0, 0, 255]
+}
+
+fn default_bar_color() -> [u8; 4] {
+    [255, 255, 255, 255]
 }
 
 /// fancier struct that we get after a bit of config post-processing
@@ -36,57 +234,426 @@ pub struct Advert {
     pub text_align: Align,
     /// left OR center of text, depending on text_align
     pub text_x: i32,
-    /// top of text
+    /// top of text, or its baseline if text_anchor_baseline is set
     pub text_y: i32,
     pub text_color: Rgba<u8>,
+    /// dark and light text colors to choose between, if the text color depends on the background
+    pub auto_contrast: Option<(Rgba<u8>, Rgba<u8>)>,
     pub text_scale: PxScale,
     pub text_case: Case,
     pub output_format: ImageOutput,
     /// prefix for GeoIP location
     pub text_prefix: String,
+    /// replacements made in the location after text_case, in order
+    pub location_substitutions: Vec<(String, String)>,
+    pub snap_baseline: bool,
+    pub text_anchor_baseline: bool,
+    pub frame_duration_ms: i32,
+    /// amplitude and frequency
+    pub wave: Option<(f32, f32)>,
+    pub alpha_threshold: Option<u8>,
+    pub qr: Option<QrOverlay>,
+    pub meter: Option<Meter>,
+    pub bar: Option<ProgressBar>,
+    /// left, top, width, and height of the rectangle the text avoids
+    pub keep_out: Option<(i32, i32, i32, i32)>,
+    pub fallback_formats: Vec<ImageOutput>,
+    pub write_metadata: bool,
+    pub content_type_override: Option<String>,
+    /// advert names by uppercase ISO country code
+    pub country_variants: HashMap<String, String>,
+    pub min_contrast_ratio: Option<f64>,
+    /// the largest GeoIP accuracy radius, in kilometers, that's trusted to name the visitor's location
+    pub min_accuracy_km: Option<u32>,
+    pub dpi: Option<u16>,
+    /// alternate images by city name or ISO country code, the same size as the main image
+    pub image_overrides: HashMap<String, DynamicImage>,
+    /// multiplied into the alpha of every frame, or of the whole sprite sheet if it's that tall
+    pub mask: Option<GrayImage>,
+    /// a tiny blurred JPEG of the first frame, served with ?placeholder=1
+    pub placeholder: Vec<u8>,
+    /// width, top color, and bottom color of the border around each frame, if it has one
+    pub border: Option<(u32, Rgba<u8>, Rgba<u8>)>,
+    /// color of the forensic watermark, if there is one, and whether to include the client's IP hash in it
+    pub watermark: Option<(Rgba<u8>, bool)>,
+    /// render as if it were always the Unix epoch, so identical requests give byte-identical output
+    pub deterministic: bool,
+    /// key for the IP hashes in watermarks and meter fills. Empty if ip_hash_key isn't set.
+    pub ip_hash_key: Vec<u8>,
+    /// not served before this
+    pub starts_at: Option<DateTime<Utc>>,
+    /// not served from this time on
+    pub expires_at: Option<DateTime<Utc>>,
+    /// margin to leave around the text when the output is cropped down to it, if it is
+    pub autocrop: Option<i32>,
 }
 
 impl Advert {
-    /// load an Advert from its definition. Notably this loads a PNG image from disk into memory
-    pub fn open(definition: AdvertDefinition) -> Advert {
-        let mut reader = ImageReader::open(definition.image.clone())
-            .unwrap_or_else(|e| panic!("failed to open image \"{}\": {:?}", definition.image, e));
-        reader.set_format(ImageFormat::Png);
-        let image = reader.decode().expect("failed to decode image");
+    /// load an Advert from its definition. Notably this loads an image from disk into memory, if it has one
+    pub fn open(name: &str, mut definition: AdvertDefinition, server: &ServerDefinition) -> Advert {
+        // frames.json promises the same frame rectangles for everyone, which autocropped frames don't have
+        if definition.autocrop_to_text && server.frame_maps {
+            panic!("advert \"{}\" has autocrop_to_text set, which can't be used with frame_maps as its frame size depends on the location", name);
+        }
+
+        // an unkeyed hash could be matched back to the IP by hashing every address there is
+        if definition.watermark && definition.watermark_ip && server.ip_hash_key.is_none() {
+            panic!("advert \"{}\" has watermark_ip set, which needs ip_hash_key to be set too", name);
+        }
+
+        // with no frames there'd be nothing to draw on, and every request would get an empty image
+        if definition.frames == 0 {
+            panic!("\"{}\" has frames = 0, but needs at least 1", name);
+        }
+
+        // frame offsets into the sprite sheet are i32 too, so the whole sheet has to fit
+        let sheet_height = definition.image_height.checked_mul(definition.frames)
+            .filter(|&height| i32::try_from(height).is_ok())
+            .unwrap_or_else(|| panic!("\"{}\" is {} frames of {}px, which is more than {}px in total", name, definition.frames, definition.image_height, i32::MAX));
+
+        let required_format = definition.require_source_format.as_deref().map(|format| {
+            ImageFormat::from_extension(format.to_ascii_lowercase())
+                .unwrap_or_else(|| panic!("require_source_format of \"{}\" is \"{}\", which isn't an image format we know", name, format))
+        });
+        let rotation = definition.source_rotation;
+        if let Some(rotation) = rotation {
+            if ![90, 180, 270].contains(&rotation) {
+                panic!("source_rotation of \"{}\" is {}, but must be 90, 180, or 270", name, rotation);
+            }
+        }
+        let image = match &definition.image {
+            Some(path) => {
+                let image = rotate(open_image(name, path, required_format), rotation);
+                check_output_loss(server, name, &image, &definition.output_format);
+                image
+            }
+            None => RgbaImage::new(definition.image_width, sheet_height).into(),
+        };
+        if let Some(path) = &definition.image {
+            let (image_width, image_height) = check_dimensions(server, name, path, &image, &definition);
+            definition.image_width = image_width;
+            definition.image_height = image_height;
+        }
+
+        let text_color = match (definition.text_color, definition.palette_text_color) {
+            (Some(text_color), _) => Rgba(text_color),
+            (None, true) => {
+                let dominant = dominant_color(&image);
+                let text_color = palette_text_color(dominant);
+                println!("[{}] Picked text color {:?} for advert \"{}\", to contrast with its dominant color {:?}", iso_string(), text_color.0, name, dominant.0);
+                text_color
+            }
+            (None, false) => panic!("advert \"{}\" needs a text_color, or palette_text_color = true", name),
+        };
+
+        let location_substitutions = definition.location_substitutions.into_iter()
+            .map(|[from, to]| {
+                if from.is_empty() {
+                    panic!("advert \"{}\" has a location_substitutions entry that replaces nothing with \"{}\"", name, to);
+                }
+                let missing: String = missing_glyphs(&to).into_iter().collect();
+                if !missing.is_empty() {
+                    config_warning(server, format!("the font has no glyphs for these characters in advert \"{}\"'s location_substitutions: {}", name, missing));
+                }
+                (from, to)
+            })
+            .collect();
+
+        let image_overrides = definition.image_overrides.into_iter()
+            .map(|(place, path)| {
+                let image_override = rotate(open_image(name, &path, required_format), rotation);
+                if image_override.dimensions() != image.dimensions() {
+                    panic!(
+                        "image_overrides image \"{}\" for \"{}\" is {}x{}, but the advert's image is {}x{}",
+                        path, place, image_override.width(), image_override.height(), image.width(), image.height()
+                    );
+                }
+                (place, image_override)
+            })
+            .collect();
+
+        let mask = definition.mask_image.map(|path| {
+            let mask_image = open_image(name, &path, None);
+            // the mask is applied to the image as it is, which image_size_mismatch lets differ from what the config says
+            let frame_size = (image.width(), image.height() / definition.frames);
+            let sheet_size = image.dimensions();
+            if mask_image.dimensions() != frame_size && mask_image.dimensions() != sheet_size {
+                panic!(
+                    "mask_image \"{}\" for \"{}\" is {}x{}, but must be {}x{} (one frame of its image) or {}x{} (the whole image)",
+                    path, name, mask_image.width(), mask_image.height(), frame_size.0, frame_size.1, sheet_size.0, sheet_size.1
+                );
+            }
+            if !definition.output_format.has_alpha() {
+                config_warning(server, format!("advert \"{}\" has a mask_image, but {:?} output can't store transparency, so masked areas will come out white", name, definition.output_format));
+            }
+            if mask_image.color().has_alpha() {
+                let alpha = mask_image.to_rgba8();
+                GrayImage::from_fn(alpha.width(), alpha.height(), |x, y| Luma([alpha.get_pixel(x, y)[3]]))
+            } else {
+                mask_image.to_luma8()
+            }
+        });
+
+        let qr = definition.qr_content.map(|content| {
+            if definition.qr_size == 0 {
+                panic!("qr_size must be set when qr_content is set");
+            }
+            QrOverlay {
+                content,
+                x: to_i32(name, "qr_x", definition.qr_x),
+                y: to_i32(name, "qr_y", definition.qr_y),
+                size: definition.qr_size,
+            }
+        });
+
+        let meter = definition.meter_width.map(|width| {
+            if definition.meter_height == 0 {
+                panic!("meter_height must be set when meter_width is set");
+            }
+            Meter {
+                x: to_i32(name, "meter_x", definition.meter_x),
+                y: to_i32(name, "meter_y", definition.meter_y),
+                width,
+                height: definition.meter_height,
+                fill: definition.meter_fill,
+                fill_color: Rgba(definition.meter_fill_color),
+                background_color: Rgba(definition.meter_background_color),
+                border_color: Rgba(definition.meter_border_color),
+                corner_radius: definition.meter_corner_radius,
+            }
+        });
+
+        let bar = definition.bar_rect.map(|[x, y, width, height]| ProgressBar {
+            x: to_i32(name, "bar_rect x", x),
+            y: to_i32(name, "bar_rect y", y),
+            width,
+            height,
+            color: Rgba(definition.bar_color),
+            background_color: definition.bar_bg_color.map(Rgba),
+        });
+
+        // there's no point falling back to the format that just failed
+        let fallback_formats = definition.fallback_formats.into_iter()
+            .filter(|format| *format != definition.output_format)
+            .collect();
+
+        let placeholder = low_quality_placeholder(&image, definition.image_height);
+
+        let starts_at = definition.starts_at.map(|time| parse_time(name, "starts_at", &time));
+        let expires_at = definition.expires_at.map(|time| parse_time(name, "expires_at", &time));
+        if let (Some(starts_at), Some(expires_at)) = (starts_at, expires_at) {
+            if starts_at >= expires_at {
+                panic!("\"{}\" expires at {}, which isn't after it starts at {}", name, expires_at, starts_at);
+            }
+        }
 
         Advert {
             image,
-            image_width: i32::try_from(definition.image_width).expect(formatcp!("image_width must be less than {}", i32::MAX)),
-            image_height: i32::try_from(definition.image_height).expect(formatcp!("image_height must be less than {}", i32::MAX)),
-            frames: i32::try_from(definition.frames).expect(formatcp!("frames must be less than {}", i32::MAX)),
+            image_width: to_i32(name, "image_width", definition.image_width),
+            image_height: to_i32(name, "image_height", definition.image_height),
+            frames: to_i32(name, "frames", definition.frames),
             text_align: definition.text_align,
-            text_x: i32::try_from(definition.text_x).expect(formatcp!("text_x must be less than {}", i32::MAX)),
-            text_y: i32::try_from(definition.text_y).expect(formatcp!("text_y must be less than {}", i32::MAX)),
-            text_color: Rgba(definition.text_color),
+            text_x: to_i32(name, "text_x", definition.text_x),
+            text_y: to_i32(name, "text_y", definition.text_y),
+            text_color,
+            auto_contrast: definition.auto_contrast.then_some((Rgba(definition.text_color_dark), Rgba(definition.text_color_light))),
             text_scale: PxScale {
                 x: definition.text_scale,
                 y: definition.text_scale,
             },
             text_case: definition.text_case,
             output_format: definition.output_format,
-            text_prefix: definition.text_prefix,
+            location_substitutions,
+            text_prefix: load_text(name, "text_prefix", definition.text_prefix),
+            snap_baseline: definition.snap_baseline,
+            text_anchor_baseline: definition.text_anchor_baseline,
+            frame_duration_ms: to_i32(name, "frame_duration_ms", definition.frame_duration_ms),
+            wave: definition.wave_amplitude.map(|amplitude| (amplitude, definition.wave_frequency)),
+            alpha_threshold: definition.alpha_threshold,
+            qr,
+            meter,
+            bar,
+            keep_out: definition.keep_out_rect.map(|[x, y, width, height]| (
+                to_i32(name, "keep_out_rect x", x),
+                to_i32(name, "keep_out_rect y", y),
+                to_i32(name, "keep_out_rect width", width),
+                to_i32(name, "keep_out_rect height", height),
+            )),
+            fallback_formats,
+            write_metadata: definition.write_metadata,
+            content_type_override: definition.content_type_override,
+            country_variants: definition.country_variants.into_iter()
+                .map(|(country, name)| (country.to_uppercase(), name))
+                .collect(),
+            min_contrast_ratio: definition.min_contrast_ratio,
+            min_accuracy_km: definition.min_accuracy_km,
+            dpi: definition.dpi,
+            image_overrides,
+            mask,
+            placeholder,
+            border: (definition.border_width > 0).then(|| {
+                let color = Rgba(definition.border_color);
+                (definition.border_width, color, definition.border_gradient_color.map_or(color, Rgba))
+            }),
+            watermark: definition.watermark.then_some((Rgba(definition.watermark_color), definition.watermark_ip)),
+            deterministic: server.deterministic,
+            ip_hash_key: server.ip_hash_key.clone().unwrap_or_default().into_bytes(),
+            starts_at,
+            expires_at,
+            autocrop: definition.autocrop_to_text.then(|| to_i32(name, "autocrop_margin", definition.autocrop_margin)),
+        }
+    }
+
+    /// the image to draw on for a visitor: an override for their city or country if there is one, or the usual image
+    pub fn image_for(&self, location: &str, country: Option<&str>) -> &DynamicImage {
+        self.image_overrides.get(location)
+            .or_else(|| country.and_then(|country| self.image_overrides.get(country)))
+            .unwrap_or(&self.image)
+    }
+
+    /// whether every render of this advert for the same location comes out the same, no matter who it's for
+    pub fn only_varies_by_location(&self) -> bool {
+        let visitor_specific = |template: &str| template.contains("{browser}") || template.contains("{os}");
+        !visitor_specific(&self.text_prefix)
+            && !self.qr.as_ref().is_some_and(|qr| visitor_specific(&qr.content))
+            && self.meter.as_ref().is_none_or(|meter| meter.fill.is_some())
+            && self.image_overrides.is_empty()
+            && self.watermark.is_none()
+    }
+
+    /// whether rendering this advert needs to know the visitor's country
+    pub fn uses_country(&self) -> bool {
+        !self.country_variants.is_empty() || !self.image_overrides.is_empty()
+    }
+
+    /// how this advert's sprite sheet divides into frames
+    pub fn animation(&self) -> Animation {
+        Animation {
+            frame_width: self.image_width as u32,
+            frame_height: self.image_height as u32,
+            frames: self.frames as u32,
+            frame_duration_ms: self.frame_duration_ms,
+        }
+    }
+}
+
+/// convert a config value to the signed type we do coordinate math in, panicking with a message that says which value
+/// of which advert is too big
+pub fn to_i32(name: &str, field: &str, value: u32) -> i32 {
+    i32::try_from(value)
+        .unwrap_or_else(|_| panic!("{} of \"{}\" is {}, but must be at most {}", field, name, value, i32::MAX))
+}
+
+/// parse an RFC 3339 timestamp from the config, panicking with a message that says which value of which advert is bad
+fn parse_time(name: &str, field: &str, time: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(time)
+        .unwrap_or_else(|e| panic!("{} of \"{}\" is \"{}\", which isn't an RFC 3339 timestamp: {}", field, name, time, e))
+        .with_timezone(&Utc)
+}
+
+/// resolve a text field that may be a `file:path` reference, which is replaced with the contents of that file minus any
+/// trailing newline
+fn load_text(name: &str, field: &str, value: String) -> String {
+    match value.strip_prefix("file:") {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .unwrap_or_else(|e| panic!("failed to read {} file \"{}\" of \"{}\": {:?}", field, path, name, e));
+            let text = text.strip_suffix('\n').unwrap_or(&text);
+            text.strip_suffix('\r').unwrap_or(text).to_owned()
         }
+        None => value,
+    }
+}
+
+/// load an image from disk into memory, going by its contents rather than trusting its file extension. If the advert
+/// requires a particular source format, anything else is an error.
+fn open_image(name: &str, path: &str, required_format: Option<ImageFormat>) -> DynamicImage {
+    let reader = ImageReader::open(path)
+        .unwrap_or_else(|e| panic!("failed to open image \"{}\": {:?}", path, e))
+        .with_guessed_format()
+        .unwrap_or_else(|e| panic!("failed to read image \"{}\": {:?}", path, e));
+    let format = reader.format()
+        .unwrap_or_else(|| panic!("image \"{}\" isn't in any format we recognize", path));
+    if let Some(required_format) = required_format {
+        if format != required_format {
+            panic!("\"{}\" requires a {:?} source, but image \"{}\" is {:?}", name, required_format, path, format);
+        }
+    }
+    let image = reader.decode().unwrap_or_else(|e| panic!("failed to decode image \"{}\": {:?}", path, e));
+    println!("[{}] Loaded \"{}\" for advert \"{}\": {:?}, {}x{}, {:?}", iso_string(), path, name, format, image.width(), image.height(), image.color());
+    image
+}
+
+/// compare an advert's image with the sprite sheet image_width, image_height, and frames describe, and deal with any
+/// difference as the server's image_size_mismatch policy says. Gives the frame width and height to use.
+fn check_dimensions(server: &ServerDefinition, name: &str, path: &str, image: &DynamicImage, definition: &AdvertDefinition) -> (u32, u32) {
+    let configured = (definition.image_width, definition.image_height);
+    if image.dimensions() == (definition.image_width, definition.image_height * definition.frames) {
+        return configured;
+    }
+
+    let message = format!(
+        "image \"{}\" for advert \"{}\" is {}x{}, but its image_width, image_height, and frames add up to {}x{}",
+        path, name, image.width(), image.height(), definition.image_width, definition.image_height * definition.frames
+    );
+    match server.image_size_mismatch {
+        ImageSizeMismatchPolicy::Warn => {
+            config_warning(server, message);
+            configured
+        }
+        ImageSizeMismatchPolicy::Error => panic!("{}", message),
+        ImageSizeMismatchPolicy::Correct => {
+            let corrected = (image.width(), image.height() / definition.frames);
+            eprintln!("[{}] WARNING: {}, so using {}x{} instead", iso_string(), message, corrected.0, corrected.1);
+            corrected
+        }
+    }
+}
+
+/// turn an image clockwise by a multiple of 90 degrees, if it's meant to be turned at all
+fn rotate(image: DynamicImage, degrees: Option<u32>) -> DynamicImage {
+    match degrees {
+        Some(90) => image.rotate90(),
+        Some(180) => image.rotate180(),
+        Some(270) => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// warn if encoding an image in the given output format will throw away some of what's in it
+fn check_output_loss(server: &ServerDefinition, name: &str, image: &DynamicImage, output_format: &ImageOutput) {
+    let color = image.color();
+    // plenty of images have an alpha channel that's opaque throughout, and those lose nothing
+    if color.has_alpha() && !output_format.has_alpha() && image.pixels().any(|(_, _, pixel)| pixel[3] < u8::MAX) {
+        config_warning(server, format!("advert \"{}\" has an image with transparency, which {:?} output will flatten onto white", name, output_format));
+    }
+    let bits_per_channel = color.bits_per_pixel() / u16::from(color.channel_count());
+    if bits_per_channel > 8 && *output_format != ImageOutput::Png {
+        config_warning(server, format!("advert \"{}\" has an image with {} bits per channel, which {:?} output will reduce to 8", name, bits_per_channel, output_format));
     }
 }
 
 /// all the different output formats we support
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug, PartialEq)]
 pub enum ImageOutput {
     Jpeg,
     Png,
+    /// lossless WebP, which is usually smaller than PNG. Sprite sheets are encoded whole, just like PNG.
+    #[serde(alias = "WebP")]
+    Webp,
+    /// each frame of the sprite sheet becomes a frame of the animation. Requires the animated-webp feature.
+    AnimatedWebp,
 }
 
 impl ImageOutput {
-    /// image "format": used by our image processing library
+    /// image "format": used by our image processing library. Animated formats give the format of their container,
+    /// which our image processing library can't actually encode an animation into.
     pub fn format(&self) -> ImageFormat {
         match &self {
             ImageOutput::Jpeg => ImageFormat::Jpeg,
             ImageOutput::Png => ImageFormat::Png,
+            ImageOutput::Webp => ImageFormat::WebP,
+            ImageOutput::AnimatedWebp => ImageFormat::WebP,
         }
     }
 
@@ -95,19 +662,31 @@ impl ImageOutput {
         match &self {
             ImageOutput::Jpeg => "image/jpeg",
             ImageOutput::Png => "image/png",
+            ImageOutput::Webp => "image/webp",
+            ImageOutput::AnimatedWebp => "image/webp",
+        }
+    }
+
+    /// whether this format can store an alpha channel
+    pub fn has_alpha(&self) -> bool {
+        match &self {
+            ImageOutput::Jpeg => false,
+            ImageOutput::Png => true,
+            ImageOutput::Webp => true,
+            ImageOutput::AnimatedWebp => true,
         }
     }
 }
 
 /// supported text alignment options
-#[derive(Deserialize)]
+#[derive(Deserialize, PartialEq)]
 pub enum Align {
     Left,
     Center,
 }
 
 /// supported text case options
-#[derive(Deserialize)]
+#[derive(Deserialize, PartialEq)]
 pub enum Case {
     /// the exact string the GeoIP lookup gives us
     Default,
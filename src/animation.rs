@@ -0,0 +1,41 @@
+use image::DynamicImage;
+
+/// how a sprite sheet divides into frames, which are stacked vertically
+#[derive(Clone, Copy)]
+pub struct Animation {
+    pub frame_width: u32,
+    pub frame_height: u32,
+    pub frames: u32,
+    #[cfg_attr(not(feature = "animated-webp"), allow(dead_code))]
+    pub frame_duration_ms: i32,
+}
+
+/// encode each frame of a rendered sprite sheet as a frame of an animated WebP, appending it to `buffer`
+#[cfg(feature = "animated-webp")]
+pub fn encode_animated_webp(image: &DynamicImage, animation: &Animation, buffer: &mut Vec<u8>) -> Result<(), String> {
+    use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+    let width = animation.frame_width;
+    let height = animation.frame_height;
+    let frames: Vec<_> = (0..animation.frames)
+        .map(|frame| image.crop_imm(0, frame * height, width, height).into_rgba8())
+        .collect();
+
+    let config = WebPConfig::new().map_err(|()| "failed to initialize WebP encoder".to_string())?;
+    let mut encoder = AnimEncoder::new(width, height, &config);
+    for (i, frame) in frames.iter().enumerate() {
+        encoder.add_frame(AnimFrame::from_rgba(frame, width, height, i as i32 * animation.frame_duration_ms));
+    }
+    let webp = encoder.try_encode()
+        .map_err(|e| format!("failed to encode animated WebP: {:?}", e))?;
+    // libwebp owns the encoded memory, so it has to be copied out either way
+    buffer.extend_from_slice(&webp);
+    Ok(())
+}
+
+/// stand-in for builds without the animated-webp feature, so adverts using it fall back to their other formats, if they
+/// have any
+#[cfg(not(feature = "animated-webp"))]
+pub fn encode_animated_webp(_image: &DynamicImage, _animation: &Animation, _buffer: &mut Vec<u8>) -> Result<(), String> {
+    Err("this build does not support animated WebP; rebuild with --features animated-webp".to_string())
+}
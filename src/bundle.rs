@@ -0,0 +1,72 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tar::{Builder, Header};
+
+use crate::config::Config;
+use crate::{iso_string, render_composite, render_location_to_image, Visitor};
+
+/// most adverts a single bundle will render
+const MAX_ADVERTS: usize = 100;
+/// most bytes of rendered images a single bundle will hold, before compression
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+/// most bundles built at once. Each one renders its adverts one after another on a single render thread, so this
+/// bounds how many render threads bundles can tie up.
+pub const MAX_CONCURRENT_BUNDLES: usize = 1;
+
+/// a gzipped tarball of adverts rendered for one visitor
+pub struct Bundle {
+    pub archive: Vec<u8>,
+    /// how many adverts were left out for going over the caps, or failing to render
+    pub skipped: usize,
+}
+
+/// render every advert and composite for a visitor, in name order, and pack the results into a .tar.gz with one file
+/// per route name
+pub fn build_bundle(config: &Config, visitor: &Visitor) -> Result<Bundle, String> {
+    let mut names: Vec<&String> = config.adverts.keys().chain(config.composites.keys()).collect();
+    names.sort();
+
+    let mtime = match config.server.deterministic {
+        true => 0,
+        false => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()),
+    };
+    let mut archive = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mut total_bytes = 0;
+    let mut skipped = names.len().saturating_sub(MAX_ADVERTS);
+    names.truncate(MAX_ADVERTS);
+    for (index, &name) in names.iter().enumerate() {
+        let image = match (config.adverts.get(name), config.composites.get(name)) {
+            (Some(advert), _) => render_location_to_image(name, advert, visitor, &config.encode_buffers),
+            (None, Some(composite)) => render_composite(composite, visitor, &config.encode_buffers),
+            (None, None) => unreachable!("bundled a name that isn't an advert or composite"),
+        };
+        let image = match image {
+            Ok((image, _)) => image,
+            Err(e) => {
+                eprintln!("[{}] left \"{}\" out of a bundle: {}", iso_string(), name, e);
+                skipped += 1;
+                continue;
+            }
+        };
+        if total_bytes + image.len() > MAX_BYTES {
+            // stop here rather than render the rest only to throw them away too
+            skipped += names.len() - index;
+            break;
+        }
+        total_bytes += image.len();
+
+        let mut header = Header::new_gnu();
+        header.set_size(image.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(mtime);
+        archive.append_data(&mut header, name, image.as_ref())
+            .map_err(|e| format!("failed to add \"{}\" to bundle: {:?}", name, e))?;
+    }
+
+    let archive = archive.into_inner()
+        .and_then(|gzip| gzip.finish())
+        .map_err(|e| format!("failed to finish bundle: {:?}", e))?;
+    Ok(Bundle { archive, skipped })
+}
@@ -0,0 +1,34 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use lru::LruCache;
+
+/// an encoded image, along with its Content-Type. The image is shared rather than copied between the cache and the
+/// responses it's sent in.
+pub type Render = (Bytes, String);
+
+/// finished renders of adverts that only vary by location, keyed by advert name and location. The least recently used
+/// are evicted once it's full.
+pub struct RenderCache {
+    renders: Mutex<LruCache<(String, String), Render>>,
+}
+
+impl RenderCache {
+    pub fn new(capacity: NonZeroUsize) -> RenderCache {
+        RenderCache {
+            renders: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn get(&self, name: &str, location: &str) -> Option<Render> {
+        self.renders.lock().unwrap()
+            .get(&(name.to_owned(), location.to_owned()))
+            .cloned()
+    }
+
+    pub fn put(&self, name: &str, location: &str, render: Render) {
+        self.renders.lock().unwrap()
+            .put((name.to_owned(), location.to_owned()), render);
+    }
+}
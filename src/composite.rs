@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use image::{DynamicImage, RgbaImage};
+use image::imageops::overlay;
+use serde::Deserialize;
+
+use crate::advert::{Advert, default_fallback_formats, default_frame_duration_ms, ImageOutput, to_i32};
+use crate::animation::Animation;
+use crate::placeholder::low_quality_placeholder;
+
+/// simple struct that maps to a config file entry of the form `composite_of = ["advert", "other advert"]`
+#[derive(Deserialize)]
+pub struct CompositeDefinition {
+    /// names of the adverts to stitch together, in order
+    pub composite_of: Vec<String>,
+    pub layout: Layout,
+    /// top left of each member within a frame, in the same order as composite_of. Only used by the Positioned layout.
+    #[serde(default)]
+    pub positions: Vec<[u32; 2]>,
+    pub output_format: ImageOutput,
+    /// formats to try, in order, if encoding to output_format fails. Defaults to Png alone. If empty, the render fails.
+    #[serde(default = "default_fallback_formats")]
+    pub fallback_formats: Vec<ImageOutput>,
+    /// how long each frame is shown for, in animated output formats
+    #[serde(default = "default_frame_duration_ms")]
+    pub frame_duration_ms: u32,
+}
+
+/// several adverts rendered separately, then stitched into one image
+pub struct Composite {
+    pub members: Vec<(String, Arc<Advert>)>,
+    /// top left of each member within a frame, in the same order as members
+    pub positions: Vec<(u32, u32)>,
+    pub output_format: ImageOutput,
+    pub fallback_formats: Vec<ImageOutput>,
+    pub animation: Animation,
+    /// a tiny blurred JPEG of the first frame of the members' images, served with ?placeholder=1
+    pub placeholder: Vec<u8>,
+}
+
+/// supported ways of arranging the members of a composite
+#[derive(Deserialize, PartialEq, Clone, Copy)]
+pub enum Layout {
+    /// top to bottom, so members must all be the same width
+    Vertical,
+    /// left to right, so members must all be the same height
+    Horizontal,
+    /// wherever `positions` says, with the frame just big enough to fit them all. Later members are drawn over earlier
+    /// ones where they overlap.
+    Positioned,
+}
+
+impl Composite {
+    /// build a Composite from its definition, checking that its members exist and can be stitched together
+    pub fn new(name: &str, definition: CompositeDefinition, adverts: &HashMap<String, Arc<Advert>>) -> Composite {
+        let members: Vec<(String, Arc<Advert>)> = definition.composite_of.into_iter()
+            .map(|member| {
+                let advert = adverts.get(&member)
+                    .unwrap_or_else(|| panic!("composite \"{}\" includes \"{}\", which does not exist", name, member))
+                    .clone();
+                (member, advert)
+            })
+            .collect();
+        let (_, first) = members.first()
+            .unwrap_or_else(|| panic!("composite \"{}\" must have at least one member", name));
+
+        for (member, advert) in &members {
+            if advert.frames != first.frames {
+                panic!("every member of composite \"{}\" must have the same number of frames, but \"{}\" has {} and \"{}\" has {}", name, members[0].0, first.frames, member, advert.frames);
+            }
+            let compatible = match definition.layout {
+                Layout::Vertical => advert.image_width == first.image_width,
+                Layout::Horizontal => advert.image_height == first.image_height,
+                Layout::Positioned => true,
+            };
+            if !compatible {
+                panic!("members of composite \"{}\" must all be the same {} for its layout, but \"{}\" and \"{}\" differ", name, definition.layout.shared_dimension(), members[0].0, member);
+            }
+        }
+
+        if definition.layout != Layout::Positioned && !definition.positions.is_empty() {
+            panic!("composite \"{}\" sets positions, which only the Positioned layout uses", name);
+        }
+        let positions: Vec<(u32, u32)> = match definition.layout {
+            Layout::Vertical => members.iter()
+                .scan(0, |y, (_, advert)| {
+                    let position = (0, *y);
+                    *y += advert.image_height as u32;
+                    Some(position)
+                })
+                .collect(),
+            Layout::Horizontal => members.iter()
+                .scan(0, |x, (_, advert)| {
+                    let position = (*x, 0);
+                    *x += advert.image_width as u32;
+                    Some(position)
+                })
+                .collect(),
+            Layout::Positioned => {
+                if definition.positions.len() != members.len() {
+                    panic!("composite \"{}\" has {} members but {} positions", name, members.len(), definition.positions.len());
+                }
+                definition.positions.iter().map(|[x, y]| (*x, *y)).collect()
+            }
+        };
+
+        // just big enough to fit every member
+        let (frame_width, frame_height) = members.iter().zip(&positions)
+            .fold((0u32, 0u32), |(width, height), ((member, advert), (x, y))| {
+                let right = x.checked_add(advert.image_width as u32);
+                let bottom = y.checked_add(advert.image_height as u32);
+                match (right, bottom) {
+                    (Some(right), Some(bottom)) => (width.max(right), height.max(bottom)),
+                    _ => panic!("member \"{}\" of composite \"{}\" is positioned too far out", member, name),
+                }
+            });
+        // the whole sprite sheet has to fit in an i32, as for adverts
+        let sheet_height = frame_height.checked_mul(first.frames as u32);
+        if i32::try_from(frame_width).is_err() || sheet_height.is_none_or(|height| i32::try_from(height).is_err()) {
+            panic!("composite \"{}\" is {} frames of {}x{}px, which is more than {}px across or in total", name, first.frames, frame_width, frame_height, i32::MAX);
+        }
+
+        let animation = Animation {
+            frame_width,
+            frame_height,
+            frames: first.frames as u32,
+            frame_duration_ms: to_i32(name, "frame_duration_ms", definition.frame_duration_ms),
+        };
+
+        // there's no point falling back to the format that just failed
+        let fallback_formats = definition.fallback_formats.into_iter()
+            .filter(|format| *format != definition.output_format)
+            .collect();
+
+        let mut composite = Composite {
+            members,
+            positions,
+            output_format: definition.output_format,
+            fallback_formats,
+            animation,
+            placeholder: Vec::new(),
+        };
+        let images: Vec<DynamicImage> = composite.members.iter()
+            .map(|(_, advert)| advert.image.clone())
+            .collect();
+        composite.placeholder = low_quality_placeholder(&composite.stitch(&images), composite.animation.frame_height);
+        composite
+    }
+
+    /// whether every render of this composite for the same location comes out the same, no matter who it's for
+    pub fn only_varies_by_location(&self) -> bool {
+        self.members.iter().all(|(_, advert)| advert.only_varies_by_location())
+    }
+
+    /// whether rendering this composite needs to know the visitor's country
+    pub fn uses_country(&self) -> bool {
+        self.members.iter().any(|(_, advert)| advert.uses_country())
+    }
+
+    /// combine the rendered images of each member, in order, into one sprite sheet with the same number of frames
+    pub fn stitch(&self, images: &[DynamicImage]) -> DynamicImage {
+        let animation = &self.animation;
+        let mut canvas = RgbaImage::new(animation.frame_width, animation.frame_height * animation.frames);
+        for frame in 0..animation.frames {
+            for (((_, advert), (x, y)), image) in self.members.iter().zip(&self.positions).zip(images) {
+                let width = advert.image_width as u32;
+                let height = advert.image_height as u32;
+                let member_frame = image.crop_imm(0, frame * height, width, height).into_rgba8();
+                overlay(&mut canvas, &member_frame, i64::from(*x), i64::from(frame * animation.frame_height + y));
+            }
+        }
+        canvas.into()
+    }
+}
+
+impl Layout {
+    /// the dimension every member must share for this layout, for error messages
+    fn shared_dimension(&self) -> &'static str {
+        match self {
+            Layout::Vertical => "width",
+            Layout::Horizontal => "height",
+            Layout::Positioned => unreachable!("positioned members don't need to share any dimension"),
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use toml::Table;
+
+use crate::advert::{Advert, AdvertDefinition, ImageOutput};
+use crate::bundle::MAX_CONCURRENT_BUNDLES;
+use crate::cache::{Render, RenderCache};
+use crate::composite::{Composite, CompositeDefinition};
+use crate::{GeoIpDatabase, iso_string, load_geoip_dbs};
+use crate::pool::BufferPool;
+use crate::text::missing_glyphs;
+use crate::throttle::RenderThrottle;
+
+/// path of the config file, relative to working directory
+const CONFIG_PATH: &str = "config.toml";
+
+/// simple struct that maps to the whole config file: optional `[server]` and `[layouts]` tables, then one table per
+/// advert
+#[derive(Deserialize)]
+pub struct ConfigDefinition {
+    #[serde(default)]
+    pub server: ServerDefinition,
+    /// named sets of advert fields, which adverts can pull in with `preset = "name"`
+    #[serde(default)]
+    pub layouts: HashMap<String, Table>,
+    /// each advert is either an [AdvertDefinition], an alias of the form `alias_of = "other advert"`, or a
+    /// [CompositeDefinition]
+    #[serde(flatten)]
+    pub adverts: HashMap<String, Table>,
+}
+
+/// process-wide settings, found in the `[server]` table
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ServerDefinition {
+    /// addresses to listen on, all serving the same routes. Defaults to 0.0.0.0:3035.
+    pub bind_addresses: Option<Vec<SocketAddr>>,
+    /// bind addresses that answer every request with a redirect to the same URL over HTTPS, for when TLS is terminated
+    /// in front of one of the other addresses
+    pub redirect_to_https: Vec<SocketAddr>,
+    /// GeoIP databases to look clients up in, in order, until one knows their city. Defaults to GeoLite2-City.mmdb.
+    pub geoip_databases: Option<Vec<String>>,
+    /// which parts of a GeoIP record to name the client's location after, tried in order. Defaults to just the city.
+    pub location_levels: Option<Vec<LocationLevel>>,
+    /// if true, missing GeoIP databases are skipped instead of failing startup
+    pub geoip_optional: bool,
+    /// enables debugging endpoints, which expose more than you'd want a random visitor to see
+    pub debug: bool,
+    /// if set, the only output formats adverts may use. Lets operators rule out formats that are expensive to encode.
+    pub allowed_output_formats: Option<Vec<ImageOutput>>,
+    /// turns config warnings (e.g. two adverts sharing an image) into startup errors
+    pub strict: bool,
+    /// accept cleartext HTTP/2 (h2c) from clients that know to use it, alongside HTTP/1.1
+    pub http2: bool,
+    /// what to do when an advert's image isn't the size its image_width, image_height, and frames add up to
+    pub image_size_mismatch: ImageSizeMismatchPolicy,
+    /// if set, further connections from an IP are refused while it has this many open
+    pub max_connections_per_ip: Option<usize>,
+    /// how long an HTTP/1.1 connection may wait for the headers of its next request. Time spent handling a request
+    /// doesn't count. 0 disables keep-alive.
+    pub keep_alive_timeout_secs: Option<u64>,
+    /// if set, requests taking longer than this many milliseconds in total get a 504 instead
+    pub request_timeout_ms: Option<u64>,
+    /// if set, each IP must wait this many milliseconds between advert renders, and gets a 429 if it doesn't
+    pub min_render_interval_ms: Option<u64>,
+    /// how many spare encode buffers to keep around for reuse, which saves reallocating them on every render
+    pub encode_buffer_pool_size: usize,
+    /// if set, the names of the adverts each host serves. Requests for any other host get a 404.
+    pub virtual_hosts: Option<HashMap<String, Vec<String>>>,
+    /// what to do with advert requests that have no User-Agent header, which is typical of bots and naive scrapers
+    pub missing_user_agent: MissingUserAgentPolicy,
+    /// if set, log a line for every request in this format, whatever route it was for and however it turned out
+    pub access_log: Option<AccessLogFormat>,
+    /// log a line for every advert served. Defaults to true. Overflows and errors are logged either way.
+    pub log_hits: Option<bool>,
+    /// match request paths exactly, rather than first collapsing repeated slashes and dropping any trailing slash
+    pub strict_paths: bool,
+    /// how many finished renders to keep, keyed by advert and location, for adverts that look the same to everyone in a
+    /// place. 0 disables the cache.
+    pub render_cache_size: usize,
+    /// make identical requests give byte-identical output, for golden-image tests, by rendering as if it were always
+    /// the Unix epoch. Only watermarks and /bundle file times depend on the clock; the encoders add no timestamps.
+    pub deterministic: bool,
+    /// secret key for hashing client IPs into watermarks and meter fills. Required by watermark_ip.
+    pub ip_hash_key: Option<String>,
+    /// render every advert at startup with a sample city and a very long one, and report how it went. Render failures
+    /// and overflows with the sample city are config warnings.
+    pub self_test: bool,
+    /// render each advert for the fallback location at startup, and serve that to every client GeoIP can't place
+    pub precache_default_city: bool,
+    /// enables /ads/<name>/<hash>, which serves a render with a year-long immutable Cache-Control if `hash` matches it,
+    /// and redirects to the URL with the right hash if not
+    pub content_hash_urls: bool,
+    /// enables /ads/<name>/frames.json, which gives the rectangle of each frame within an advert's sprite sheet
+    pub frame_maps: bool,
+}
+
+/// supported ways of handling an advert image that isn't the size its config says
+#[derive(Deserialize, Default, PartialEq)]
+pub enum ImageSizeMismatchPolicy {
+    /// log a config warning (fatal in strict mode), then go by the config anyway
+    #[default]
+    Warn,
+    /// fail startup
+    Error,
+    /// log a warning, then go by the image: its width, and its height split evenly between the frames
+    Correct,
+}
+
+/// formats the access log can be written in
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AccessLogFormat {
+    /// the Common Log Format: client IP, time, request line, status, and response size
+    Common,
+    /// the Common Log Format plus the Referer and User-Agent, as Apache and nginx write by default
+    Combined,
+    /// one JSON object per line, with the same fields as Combined
+    Json,
+}
+
+/// supported ways of handling advert requests without a User-Agent
+#[derive(Deserialize, Default, PartialEq)]
+pub enum MissingUserAgentPolicy {
+    /// serve them like any other request
+    #[default]
+    Allow,
+    /// respond with 403 Forbidden
+    Reject,
+    /// serve the advert, but with the fallback location instead of a GeoIP lookup
+    Placeholder,
+}
+
+/// parts of a GeoIP record that can name a client's location, from most to least specific
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LocationLevel {
+    City,
+    /// the largest subdivision of the country the client is in, such as a US state or an English county
+    Subdivision,
+    Country,
+}
+
+impl ServerDefinition {
+    /// which parts of a GeoIP record to name the client's location after, in order of preference
+    pub fn location_levels(&self) -> &[LocationLevel] {
+        self.location_levels.as_deref().unwrap_or(&[LocationLevel::City])
+    }
+
+    /// whether to log a line for every advert served
+    pub fn log_hits(&self) -> bool {
+        self.log_hits.unwrap_or(true)
+    }
+
+    /// whether the server policy permits encoding to the given format
+    pub fn allows_output_format(&self, format: &ImageOutput) -> bool {
+        self.allowed_output_formats.as_ref()
+            .is_none_or(|allowed| allowed.contains(format))
+    }
+}
+
+/// everything the request handlers need, loaded once at startup
+pub struct Config {
+    pub server: ServerDefinition,
+    /// aliases share the [Advert] of their target, so the image is only in memory once
+    pub adverts: HashMap<String, Arc<Advert>>,
+    /// served at /ads/<name> just like adverts, but built out of several of them
+    pub composites: HashMap<String, Arc<Composite>>,
+    /// vanity paths, mapped to the name of the advert they serve
+    pub paths: HashMap<String, String>,
+    pub encode_buffers: BufferPool,
+    /// None if min_render_interval_ms isn't set
+    pub throttle: Option<RenderThrottle>,
+    /// in lookup order. Empty if GeoIP is disabled, in which case every lookup uses the fallback location.
+    pub geoip: Vec<GeoIpDatabase>,
+    /// adverts already rendered for the fallback location, with their Content-Type, by advert name. Only filled in if
+    /// precache_default_city is set, and only for adverts that look the same to everyone GeoIP can't place.
+    pub default_renders: HashMap<String, Render>,
+    /// None if render_cache_size is 0
+    pub render_cache: Option<RenderCache>,
+    /// a permit for each /bundle that may be built at once
+    pub bundle_permits: Arc<Semaphore>,
+}
+
+impl Config {
+    /// whether the advert or composite called `name` may be served to a request for `host`
+    pub fn serves(&self, host: Option<&str>, name: &str) -> bool {
+        match &self.server.virtual_hosts {
+            None => true,
+            Some(virtual_hosts) => host
+                .and_then(|host| virtual_hosts.get(host))
+                .is_some_and(|names| names.iter().any(|served| served == name)),
+        }
+    }
+}
+
+/// load the config file, along with the images and GeoIP databases it references
+pub fn load_config() -> Config {
+    let config = fs::read_to_string(CONFIG_PATH).unwrap_or_else(|e| panic!("failed to open {}: {:?}", CONFIG_PATH, e));
+    let mut config: ConfigDefinition = toml::from_str(&config).unwrap_or_else(|e| panic!("failed to deserialize {}: {}", CONFIG_PATH, e));
+
+    if config.server.location_levels.as_ref().is_some_and(Vec::is_empty) {
+        panic!("location_levels is empty, so no client could ever be located");
+    }
+
+    // sort real adverts from aliases, setting the aliases aside until all their potential targets exist
+    let mut definitions: Vec<(String, AdvertDefinition)> = Vec::new();
+    let mut aliases: HashMap<String, String> = HashMap::new();
+    let mut composite_definitions: Vec<(String, CompositeDefinition)> = Vec::new();
+    for (name, table) in config.adverts {
+        if table.contains_key("composite_of") {
+            let definition: CompositeDefinition = toml::Value::Table(table).try_into()
+                .unwrap_or_else(|e| panic!("failed to deserialize composite \"{}\": {}", name, e));
+            check_output_formats(&config.server, &name, &definition.output_format, &definition.fallback_formats);
+            composite_definitions.push((name, definition));
+            continue;
+        }
+
+        match table.get("alias_of") {
+            Some(target) => {
+                let target = target.as_str()
+                    .unwrap_or_else(|| panic!("alias_of in advert \"{}\" must be a string", name));
+                if table.len() > 1 {
+                    panic!("advert \"{}\" is an alias, so it must not have any fields other than alias_of", name);
+                }
+                aliases.insert(name, target.to_owned());
+            }
+            None => {
+                let table = apply_preset(&name, table, &config.layouts);
+                let definition: AdvertDefinition = toml::Value::Table(table).try_into()
+                    .unwrap_or_else(|e| panic!("failed to deserialize advert \"{}\": {}", name, e));
+                check_output_formats(&config.server, &name, &definition.output_format, &definition.fallback_formats);
+                if let Some(required) = &definition.require_glyphs {
+                    let missing = missing_glyphs(required);
+                    if !missing.is_empty() {
+                        let missing: String = missing.into_iter().collect();
+                        config_warning(&config.server, format!("the font has no glyphs for these characters required by advert \"{}\": {}", name, missing));
+                    }
+                }
+                if let Some(content_type) = &definition.content_type_override {
+                    config_warning(&config.server, format!("advert \"{}\" will claim to be {} no matter what format it's actually encoded in", name, content_type));
+                }
+                definitions.push((name, definition));
+            }
+        }
+    }
+
+    definitions.sort_by(|(a, _), (b, _)| a.cmp(b));
+    check_for_duplicates(&config.server, &definitions);
+    let paths = collect_paths(&definitions);
+
+    let mut adverts: HashMap<String, Arc<Advert>> = definitions.into_iter()
+        .map(|(name, definition)| {
+            let advert = Advert::open(&name, definition, &config.server);
+            (name, Arc::new(advert))
+        })
+        .collect();
+
+    for name in aliases.keys() {
+        let target = resolve_alias(name, &aliases);
+        let advert = adverts.get(target)
+            .unwrap_or_else(|| panic!("advert \"{}\" is an alias of \"{}\", which does not exist", name, target))
+            .clone();
+        adverts.insert(name.clone(), advert);
+    }
+
+    let composites: HashMap<String, Arc<Composite>> = composite_definitions.into_iter()
+        .map(|(name, definition)| {
+            let composite = Composite::new(&name, definition, &adverts);
+            (name, Arc::new(composite))
+        })
+        .collect();
+
+    // host names are case-insensitive, and we compare them lowercased
+    if let Some(virtual_hosts) = config.server.virtual_hosts.take() {
+        let virtual_hosts = virtual_hosts.into_iter()
+            .map(|(host, names)| {
+                for name in &names {
+                    if !adverts.contains_key(name) && !composites.contains_key(name) {
+                        panic!("virtual host \"{}\" serves \"{}\", which does not exist", host, name);
+                    }
+                }
+                (host.to_ascii_lowercase(), names)
+            })
+            .collect();
+        config.server.virtual_hosts = Some(virtual_hosts);
+    }
+
+    for (name, advert) in &adverts {
+        for (country, variant) in &advert.country_variants {
+            if !adverts.contains_key(variant) {
+                panic!("advert \"{}\" uses \"{}\" for country {}, which does not exist", name, variant, country);
+            }
+        }
+    }
+
+    Config {
+        geoip: load_geoip_dbs(&config.server),
+        encode_buffers: BufferPool::new(config.server.encode_buffer_pool_size),
+        throttle: config.server.min_render_interval_ms.map(|interval| RenderThrottle::new(Duration::from_millis(interval))),
+        render_cache: NonZeroUsize::new(config.server.render_cache_size).map(RenderCache::new),
+        bundle_permits: Arc::new(Semaphore::new(MAX_CONCURRENT_BUNDLES)),
+        server: config.server,
+        adverts,
+        composites,
+        paths,
+        default_renders: HashMap::new(),
+    }
+}
+
+/// fill in any fields an advert doesn't set itself from the layout preset it names, if it names one
+fn apply_preset(name: &str, mut table: Table, layouts: &HashMap<String, Table>) -> Table {
+    let preset = match table.remove("preset") {
+        Some(preset) => preset,
+        None => return table,
+    };
+    let preset = preset.as_str()
+        .unwrap_or_else(|| panic!("preset in advert \"{}\" must be a string", name));
+    let layout = layouts.get(preset)
+        .unwrap_or_else(|| panic!("advert \"{}\" uses preset \"{}\", which is not in [layouts]", name, preset));
+    for (key, value) in layout {
+        if !table.contains_key(key) {
+            table.insert(key.clone(), value.clone());
+        }
+    }
+    table
+}
+
+/// make sure an advert or composite only uses output formats the server allows, and can actually encode
+fn check_output_formats(server: &ServerDefinition, name: &str, output_format: &ImageOutput, fallback_formats: &[ImageOutput]) {
+    if !server.allows_output_format(output_format) {
+        panic!("advert \"{}\" uses output_format {:?}, which is not in allowed_output_formats", name, output_format);
+    }
+    if let Some(format) = fallback_formats.iter().find(|format| !server.allows_output_format(format)) {
+        panic!("advert \"{}\" has {:?} in fallback_formats, which is not in allowed_output_formats", name, format);
+    }
+    if cfg!(not(feature = "animated-webp")) && *output_format == ImageOutput::AnimatedWebp {
+        let consequence = match fallback_formats.is_empty() {
+            true => "and it has no fallback_formats, so every render of it will fail",
+            false => "so it will always use its fallback_formats",
+        };
+        config_warning(server, format!("advert \"{}\" uses output_format AnimatedWebp, but this build doesn't support it, {}", name, consequence));
+    }
+}
+
+/// report a questionable but survivable config problem: fatal in strict mode, just a warning otherwise
+pub fn config_warning(server: &ServerDefinition, message: String) {
+    if server.strict {
+        panic!("{} (this is an error because strict mode is enabled)", message);
+    } else {
+        eprintln!("[{}] WARNING: {}", iso_string(), message);
+    }
+}
+
+/// look for adverts that share an image, which is often a sign of a copy-paste mistake
+fn check_for_duplicates(server: &ServerDefinition, definitions: &[(String, AdvertDefinition)]) {
+    for (i, (name_a, a)) in definitions.iter().enumerate() {
+        for (name_b, b) in &definitions[i + 1..] {
+            // adverts without an image are drawn on a blank canvas, so there's nothing to share
+            let image = match (&a.image, &b.image) {
+                (Some(image_a), Some(image_b)) if image_a == image_b => image_a,
+                _ => continue,
+            };
+
+            let message = if a == b {
+                format!("adverts \"{}\" and \"{}\" are identical. Consider making one an alias_of the other.", name_a, name_b)
+            } else if (a.image_width, a.image_height, a.frames) != (b.image_width, b.image_height, b.frames) {
+                format!("adverts \"{}\" and \"{}\" use the same image \"{}\", but disagree on its dimensions or frame count", name_a, name_b, image)
+            } else {
+                format!("adverts \"{}\" and \"{}\" use the same image \"{}\"", name_a, name_b, image)
+            };
+            config_warning(server, message);
+        }
+    }
+}
+
+/// gather the adverts' vanity paths, making sure each one is only claimed once and doesn't shadow a built-in route
+fn collect_paths(definitions: &[(String, AdvertDefinition)]) -> HashMap<String, String> {
+    let mut paths: HashMap<String, String> = HashMap::new();
+    for (name, definition) in definitions {
+        if let Some(path) = &definition.path {
+            if !path.starts_with('/') || path.ends_with('/') {
+                panic!("path \"{}\" of advert \"{}\" must start with a / and must not end with one", path, name);
+            }
+            if path.starts_with("/ads/") || path == "/geoip" || path == "/bundle" {
+                panic!("path \"{}\" of advert \"{}\" collides with a built-in route", path, name);
+            }
+            if let Some(other) = paths.insert(path.clone(), name.clone()) {
+                panic!("adverts \"{}\" and \"{}\" both use the path \"{}\"", other, name, path);
+            }
+        }
+    }
+    paths
+}
+
+/// follow a chain of aliases to its end, panicking if it loops back on itself
+fn resolve_alias<'a>(name: &'a str, aliases: &'a HashMap<String, String>) -> &'a str {
+    let mut chain = vec![name];
+    let mut current = name;
+    while let Some(target) = aliases.get(current) {
+        let cycle = chain.contains(&target.as_str());
+        chain.push(target);
+        if cycle {
+            panic!("advert aliases form a cycle: {}", chain.join(" -> "));
+        }
+        current = target;
+    }
+    current
+}
@@ -2,50 +2,148 @@
 extern crate lazy_static;
 
 use std::collections::HashMap;
-use std::fs;
 use std::io::Cursor;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
-use ab_glyph::FontVec;
-use chrono::{SecondsFormat, Utc};
-use imageproc::drawing::{draw_text_mut, text_size};
-use maxminddb::{geoip2, Reader as MaxMindReader};
-use warp::Filter;
+use ab_glyph::{FontVec, PxScale};
+use bytes::Bytes;
+use image::{ColorType, DynamicImage, GenericImage, ImageFormat, Rgba, RgbaImage, RgbImage};
+use chrono::{DateTime, SecondsFormat, Utc};
+use maxminddb::{geoip2, MaxMindDBError, Reader as MaxMindReader};
+use serde::{Deserialize, Serialize};
+use warp::{Filter, Reply};
+use warp::path::FullPath;
 use warp::http::{Response, StatusCode};
 
 use crate::advert::*;
+use crate::animation::{Animation, encode_animated_webp};
+use crate::bundle::build_bundle;
+use crate::cache::Render;
+use crate::composite::Composite;
+use crate::config::{Config, load_config, LocationLevel, MissingUserAgentPolicy, ServerDefinition};
+use crate::iphash::ip_hash;
+use crate::metadata::{embed_metadata, set_dpi};
+use crate::pool::BufferPool;
+use crate::meter::{draw_meter, draw_progress_bar};
+use crate::qr::draw_qr;
+use crate::selftest::self_test;
+use crate::server::{client_headers, ClientHeaders, host, remote, request_phase, RequestPhase, serve};
+use crate::shape::{RoundedRect, stroke_rect_gradient};
+use crate::text::{contrast_ratio, contrasting_color, draw_text, fill_template, text_extent, text_size, TextStyle, Wave};
 
 mod advert;
+mod animation;
+mod bundle;
+mod cache;
+mod composite;
+mod config;
+mod iphash;
+mod metadata;
+mod placeholder;
+mod pool;
+mod meter;
+mod qr;
+mod selftest;
+mod server;
+mod shape;
+mod text;
+mod throttle;
 
 /// fallback fake location for when GeoIP lookup fails
 const DEFAULT_CITY: &str = "your area";
 
-type GeoIp = MaxMindReader<Vec<u8>>;
-type Config = HashMap<String, Advert>;
+/// fallback fake browser for when the User-Agent is missing or unrecognized
+const DEFAULT_BROWSER: &str = "your browser";
+
+/// fallback fake operating system for when the User-Agent is missing or unrecognized
+const DEFAULT_OS: &str = "your computer";
+
+/// address the server listens on if bind_addresses isn't configured
+const DEFAULT_SERVER_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3035);
+
+/// path of the GeoIP database used if none are configured, relative to working directory
+const GEOIP_PATH: &str = "GeoLite2-City.mmdb";
+
+/// size of forensic watermark text, in pixels
+const WATERMARK_SCALE: f32 = 10.0;
+
+/// gap between a forensic watermark and the edges of the frame, in pixels
+const WATERMARK_MARGIN: i32 = 2;
+
+/// space left between moved text and the keep-out rectangle it was moved away from, in pixels
+const KEEP_OUT_GAP: i32 = 4;
+
+/// a loaded GeoIP database, along with where it came from
+struct GeoIpDatabase {
+    path: String,
+    reader: MaxMindReader<Vec<u8>>,
+}
+
+/// everything we've worked out about whoever requested an advert
+#[derive(Clone)]
+pub struct Visitor {
+    pub ip: IpAddr,
+    pub location: String,
+    pub browser: String,
+    pub os: String,
+    /// uppercase ISO country code, if known
+    pub country: Option<String>,
+}
 
 lazy_static! {
     static ref FONT: FontVec = FontVec::try_from_vec(Vec::from(include_bytes!("resources/DejaVuSans-Bold.ttf") as &[u8])).unwrap();
-    static ref GEOIP: GeoIp = load_geoip_db();
+    /// a 1x1 fully transparent PNG
+    static ref PIXEL: Vec<u8> = {
+        let mut buffer: Vec<u8> = Vec::new();
+        DynamicImage::from(RgbaImage::new(1, 1)).write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png).unwrap();
+        buffer
+    };
 }
 
-fn load_geoip_db() -> GeoIp {
-    maxminddb::Reader::open_readfile("GeoLite2-City.mmdb")
-        .expect("failed to load geoip database")
+/// load the GeoIP databases in lookup order, skipping any that are missing if the config says that's okay
+fn load_geoip_dbs(server: &ServerDefinition) -> Vec<GeoIpDatabase> {
+    let paths = server.geoip_databases.clone().unwrap_or_else(|| vec![GEOIP_PATH.to_owned()]);
+    let databases: Vec<GeoIpDatabase> = paths.into_iter()
+        .filter(|path| {
+            let missing = server.geoip_optional && !Path::new(path).exists();
+            if missing {
+                eprintln!("[{}] WARNING: {} not found, so it will be skipped", iso_string(), path);
+            }
+            !missing
+        })
+        .map(|path| {
+            let reader = maxminddb::Reader::open_readfile(&path)
+                .unwrap_or_else(|e| panic!("failed to load geoip database \"{}\": {:?}", path, e));
+            GeoIpDatabase { path, reader }
+        })
+        .collect();
+
+    if databases.is_empty() {
+        eprintln!("[{}] WARNING: no GeoIP databases loaded. GeoIP is DISABLED and every client will be shown \"{}\"", iso_string(), DEFAULT_CITY);
+    }
+    databases
 }
 
 #[tokio::main]
 async fn main() {
     println!("[{}] Initializing {} {}", iso_string(), env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
 
-    let server_address: SocketAddr = ([0, 0, 0, 0], 3035).into();
+    // parse the font up front rather than on the first request, and see how long it takes
+    let font_start = Instant::now();
+    lazy_static::initialize(&FONT);
+    println!("[{}] Loaded font in {}ms", iso_string(), font_start.elapsed().as_millis());
 
     // load the config file and referenced images
-    let config = fs::read_to_string("config.toml").expect("failed to open config.toml");
-    let config: HashMap<String, AdvertDefinition> = toml::from_str(&config).expect("failed to deserialize config.toml");
-    let config: Config = config.into_iter()
-        .map(|(path, ad)| (path, Advert::open(ad)))
-        .collect();
+    let mut config = load_config();
+    if config.server.self_test {
+        self_test(&config);
+    }
+    if config.server.precache_default_city {
+        config.default_renders = precache_default_city(&config);
+    }
     let config = Arc::new(config);
 
     println!("[{}] Done loading images", iso_string());
@@ -58,17 +156,97 @@ async fn main() {
     // the advert endpoint, hosted at /ads/<image_name>
     let adverts = warp::path!("ads" / String)
         .and(warp::get())
+        .and(warp::query::<AdvertQuery>())
         .and(with_state(config.clone()))
-        .and(warp::filters::addr::remote())
+        .and(remote())
+        .and(client_headers())
+        .and(request_phase())
+        .and(host())
         .and_then(fake_advert_handler);
 
+    // the advert endpoint again, but at an immutable URL naming the render's content, hosted at /ads/<image_name>/<hash>
+    let hashed_adverts = warp::path!("ads" / String / String)
+        .and(warp::get())
+        .and(warp::query::<AdvertQuery>())
+        .and_then(hashed_advert_path)
+        .untuple_one()
+        .and(with_state(config.clone()))
+        .and(remote())
+        .and(client_headers())
+        .and(request_phase())
+        .and(host())
+        .and_then(fake_advert_handler);
+
+    // sprite sheet layout, so front-ends can animate an advert with CSS, hosted at /ads/<image_name>/sprite.json
+    let sprite = warp::path!("ads" / String / "sprite.json")
+        .and(warp::get())
+        .and(with_state(config.clone()))
+        .and(host())
+        .and_then(sprite_handler);
+
+    // where each frame sits in the sprite sheet, for tooling that slices it up, hosted at /ads/<image_name>/frames.json
+    let frames = warp::path!("ads" / String / "frames.json")
+        .and(warp::get())
+        .and(with_state(config.clone()))
+        .and(host())
+        .and_then(frames_handler);
+
+    // impression counting, hosted at /ads/<image_name>/pixel
+    let pixel = warp::path!("ads" / String / "pixel")
+        .and(warp::get())
+        .and(with_state(config.clone()))
+        .and(remote())
+        .and(host())
+        .and_then(pixel_handler);
+
+    // debug endpoint dumping the full GeoIP record, hosted at /geoip?ip=<address>
+    let geoip = warp::path!("geoip")
+        .and(warp::get())
+        .and(warp::query::<GeoIpQuery>())
+        .and(with_state(config.clone()))
+        .and(remote())
+        .and_then(geoip_handler);
+
+    // debug endpoint rendering every advert for a city into one .tar.gz, hosted at /bundle?city=<name>
+    let bundle = warp::path!("bundle")
+        .and(warp::get())
+        .and(warp::query::<BundleQuery>())
+        .and(with_state(config.clone()))
+        .and(remote())
+        .and(warp::header::optional::<String>("user-agent"))
+        .and_then(bundle_handler);
+
+    // adverts with a vanity path configured are also served there
+    let vanity = warp::path::full()
+        .and(warp::get())
+        .and(warp::query::<AdvertQuery>())
+        .and(with_state(config.clone()))
+        .and(remote())
+        .and(client_headers())
+        .and(request_phase())
+        .and(host())
+        .and_then(vanity_path_handler);
+
     let routes = info
-        .or(adverts);
+        .or(adverts)
+        .or(sprite)
+        .or(frames)
+        .or(pixel)
+        .or(hashed_adverts)
+        .or(geoip)
+        .or(bundle)
+        .or(vanity);
 
-    println!("[{}] Starting web server on {}...", iso_string(), server_address);
-    warp::serve(routes)
-        .run(server_address)
-        .await;
+    let server_addresses = config.server.bind_addresses.clone().unwrap_or_else(|| vec![DEFAULT_SERVER_ADDRESS]);
+    if server_addresses.is_empty() {
+        panic!("bind_addresses is empty, so there's nothing to listen on");
+    }
+    if let Some(address) = config.server.redirect_to_https.iter().find(|address| !server_addresses.contains(address)) {
+        panic!("redirect_to_https lists {}, which isn't one of the bind_addresses", address);
+    }
+    let address_list = server_addresses.iter().map(SocketAddr::to_string).collect::<Vec<_>>().join(", ");
+    println!("[{}] Starting web server on {}...", iso_string(), address_list);
+    serve(routes, &server_addresses, &config.server).await;
 }
 
 /// helper function making it easier to pass state warp filters
@@ -81,110 +259,1204 @@ fn iso_string() -> String {
     Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
 }
 
-/// handles a request to the /ad/<image_name> endpoint
-async fn fake_advert_handler(image_name: String, config: Arc<Config>, socket_addr: Option<SocketAddr>) -> Result<impl warp::Reply, warp::Rejection> {
-    match (*config).get(&image_name) {
-        Some(advert) => {
+/// something that can be served at /ads/<image_name>
+#[derive(Clone)]
+enum Servable {
+    Advert(Arc<Advert>),
+    Composite(Arc<Composite>),
+}
+
+/// query string for the advert endpoints
+#[derive(Deserialize)]
+struct AdvertQuery {
+    /// if "1" or "true", respond with a tiny blurred placeholder instead of the advert
+    placeholder: Option<String>,
+    /// if "1" or "true", respond with a multipart/mixed body holding JSON metadata about the render, then the image
+    multipart: Option<String>,
+    /// the content hash from an /ads/<image_name>/<hash> path. Never read from the query string itself.
+    #[serde(skip)]
+    hash: Option<String>,
+}
+
+impl AdvertQuery {
+    fn wants_placeholder(&self) -> bool {
+        matches!(self.placeholder.as_deref(), Some("1" | "true"))
+    }
+
+    fn wants_multipart(&self) -> bool {
+        matches!(self.multipart.as_deref(), Some("1" | "true"))
+    }
+}
+
+/// what got rendered for a request, sent as JSON alongside the image in multipart responses
+#[derive(Serialize)]
+struct RenderMetadata {
+    /// the advert actually rendered, which differs from the one requested if a country variant was swapped in
+    advert: String,
+    location: String,
+    frame_width: i32,
+    frame_height: i32,
+    frames: i32,
+    content_type: String,
+}
+
+impl RenderMetadata {
+    fn new(advert: String, servable: &Servable, visitor: &Visitor, content_type: &str) -> RenderMetadata {
+        let (frame_width, frame_height, frames) = match servable {
+            Servable::Advert(advert) => {
+                let (frame_width, frame_height) = frame_size(advert, visitor);
+                (frame_width, frame_height, advert.frames)
+            }
+            Servable::Composite(composite) => {
+                let animation = &composite.animation;
+                (animation.frame_width as i32, animation.frame_height as i32, animation.frames as i32)
+            }
+        };
+        RenderMetadata {
+            advert,
+            location: visitor.location.clone(),
+            frame_width,
+            frame_height,
+            frames,
+            content_type: content_type.to_owned(),
+        }
+    }
+}
+
+/// package an image and its metadata as a multipart/mixed body: the JSON metadata first, then the image. Returns the
+/// Content-Type, which carries the boundary, and the body.
+fn multipart_body(image: &[u8], content_type: &str, metadata: &RenderMetadata) -> (String, Vec<u8>) {
+    let json = serde_json::to_vec(metadata).expect("failed to serialize render metadata");
+
+    // the boundary must not appear in either part, so make one that doesn't
+    let mut boundary = String::from("singles-in-your-area");
+    while contains(image, boundary.as_bytes()) || contains(&json, boundary.as_bytes()) {
+        boundary.push('-');
+    }
+
+    let mut body: Vec<u8> = Vec::with_capacity(image.len() + json.len() + 256);
+    for (part_type, part) in [("application/json", json.as_slice()), (content_type, image)] {
+        body.extend_from_slice(format!("--{}\r\nContent-Type: {}\r\n\r\n", boundary, part_type).as_bytes());
+        body.extend_from_slice(part);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (format!("multipart/mixed; boundary=\"{}\"", boundary), body)
+}
+
+/// whether `needle` appears anywhere in `haystack`
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|window| window == needle)
+}
+
+/// takes the hash out of an /ads/<image_name>/<hash> path and puts it in the query, so the request can be handled like
+/// any other advert request. Anything that isn't shaped like a hash is left for other routes.
+async fn hashed_advert_path(image_name: String, hash: String, mut query: AdvertQuery) -> Result<(String, AdvertQuery), warp::Rejection> {
+    if hash.len() != 8 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(warp::reject::not_found());
+    }
+    query.hash = Some(hash.to_ascii_lowercase());
+    Ok((image_name, query))
+}
+
+/// the content hash used in /ads/<image_name>/<hash> URLs: a CRC32 of the encoded image, as 8 lowercase hex digits. The
+/// location is drawn into the image, so renders for different places get different hashes.
+fn content_hash(image: &[u8]) -> String {
+    format!("{:08x}", crc32fast::hash(image))
+}
+
+/// handles a request to the /ad/<image_name> endpoint, or to /ads/<image_name>/<hash> if the query has a hash
+async fn fake_advert_handler(image_name: String, query: AdvertQuery, config: Arc<Config>, socket_addr: Option<SocketAddr>, headers: ClientHeaders, phase: RequestPhase, host: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    let servable = config.adverts.get(&image_name).map(|advert| Servable::Advert(advert.clone()))
+        .or_else(|| config.composites.get(&image_name).map(|composite| Servable::Composite(composite.clone())))
+        .filter(|_| config.serves(host.as_deref(), &image_name));
+    let ClientHeaders { user_agent, accepts_json } = headers;
+    match servable {
+        Some(mut servable) => {
+            if user_agent.is_none() && config.server.missing_user_agent == MissingUserAgentPolicy::Reject {
+                eprintln!("[{}] 403: {} requested without a User-Agent", iso_string(), image_name);
+                return Ok(error_response(StatusCode::FORBIDDEN, "a User-Agent is required", accepts_json));
+            }
+
+            if let Servable::Advert(advert) = &servable {
+                let now = Utc::now();
+                if advert.expires_at.is_some_and(|expires_at| now >= expires_at) {
+                    eprintln!("[{}] 410: {} has expired", iso_string(), image_name);
+                    return Ok(error_response(StatusCode::GONE, "this advert has expired", accepts_json));
+                }
+                if advert.starts_at.is_some_and(|starts_at| now < starts_at) {
+                    eprintln!("[{}] 404: {} hasn't started yet", iso_string(), image_name);
+                    return Ok(error_response(StatusCode::NOT_FOUND, "resource not found on server", accepts_json));
+                }
+            }
 
-            // attempt to generate the image
-            let image = socket_addr
-                .ok_or_else(|| "no remote address".to_string())
-                .and_then(|socket_addr| {
-                    render_location_to_image(advert, get_city_from_ip(socket_addr.ip()))
-                        .map_err(|e| format!("Error encoding PNG: {:?}", e))
-                });
+            if query.hash.is_some() && !config.server.content_hash_urls {
+                return Err(warp::reject::not_found());
+            }
+
+            // renders that vary by more than location would never match their hash, so they don't get hashed URLs
+            let cacheable = match &servable {
+                Servable::Advert(advert) => advert.only_varies_by_location(),
+                Servable::Composite(composite) => composite.only_varies_by_location(),
+            };
+            if query.hash.is_some() && !cacheable {
+                eprintln!("[{}] 404: {} has no content hash URL", iso_string(), image_name);
+                return Ok(error_response(StatusCode::NOT_FOUND, "resource not found on server", accepts_json));
+            }
 
-            match image {
-                Ok(image) => {
+            // placeholders are made up front, so there's no render to throttle
+            if query.wants_placeholder() {
+                let placeholder = match &servable {
+                    Servable::Advert(advert) => advert.placeholder.clone(),
+                    Servable::Composite(composite) => composite.placeholder.clone(),
+                };
+                return Ok(
+                    Response::builder()
+                        .status(StatusCode::OK)
+                        .header("Content-Type", "image/jpeg")
+                        .body(Bytes::from(placeholder))
+                );
+            }
+
+            if let (Some(throttle), Some(socket_addr)) = (&config.throttle, socket_addr) {
+                if let Err(wait) = throttle.try_render(socket_addr.ip()) {
+                    eprintln!("[{}] 429: {} requested {} too soon after their last render", iso_string(), socket_addr.ip(), image_name);
+                    let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, "too many requests", accepts_json);
+                    if let Ok(response) = &mut response {
+                        response.headers_mut().insert("Retry-After", wait.as_secs_f64().ceil().to_string().parse().unwrap());
+                    }
+                    return Ok(response);
+                }
+            }
+
+            let socket_addr = match socket_addr {
+                Some(socket_addr) => socket_addr,
+                None => {
+                    eprintln!("[{}] no remote address", iso_string());
+                    return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, "no remote address", accepts_json));
+                }
+            };
+
+            // the GeoIP databases are in memory, so looking the visitor up is quick enough to do here. That way a cached
+            // render is served without ever waiting for a render thread.
+            phase.set("looking up the location");
+            let placeholder = user_agent.is_none() && config.server.missing_user_agent == MissingUserAgentPolicy::Placeholder;
+
+            // each database is only asked once, and the country and location both come from what it says
+            let records = match placeholder {
+                true => Vec::new(),
+                false => lookup_records(&config.geoip, socket_addr.ip()),
+            };
+            let uses_country = match &servable {
+                Servable::Advert(advert) => advert.uses_country(),
+                Servable::Composite(composite) => composite.uses_country(),
+            };
+            let country = uses_country.then(|| country_from_records(&records)).flatten();
+
+            // swap in the variant for the visitor's country, if there is one
+            let mut render_name = image_name.clone();
+            if let Servable::Advert(advert) = &servable {
+                if !advert.country_variants.is_empty() {
+                    let variant = country.as_ref()
+                        .and_then(|country| advert.country_variants.get(country))
+                        .and_then(|variant| config.adverts.get_key_value(variant));
+                    if let Some((variant_name, variant)) = variant {
+                        render_name = variant_name.clone();
+                        servable = Servable::Advert(variant.clone());
+                    }
+                }
+            }
+
+            let max_accuracy_km = match &servable {
+                Servable::Advert(advert) => advert.min_accuracy_km,
+                Servable::Composite(_) => None,
+            };
+            let location = city_from_records(&records, config.server.location_levels(), max_accuracy_km);
+            // adverts that look the same to everyone in a place can reuse an earlier render for that place. A composite's
+            // members are fixed, so its own name is enough to key it by.
+            let cacheable = match &servable {
+                Servable::Advert(advert) => advert.only_varies_by_location(),
+                Servable::Composite(composite) => composite.only_varies_by_location(),
+            };
+            let cached = config.default_renders.get(&render_name)
+                .filter(|_| location == DEFAULT_CITY)
+                .cloned()
+                .or_else(|| config.render_cache.as_ref().filter(|_| cacheable).and_then(|cache| cache.get(&render_name, &location)));
+
+            let (browser, os) = get_browser_and_os(user_agent.as_deref());
+            let visitor = Visitor {
+                ip: socket_addr.ip(),
+                location,
+                browser,
+                os,
+                country,
+            };
+
+            let render = match cached {
+                Some(cached) => Ok(cached),
+                None => {
+                    // rendering is all blocking work, so it goes on its own thread where it can't hold up other requests,
+                    // and where the request timeout can give up on it
+                    phase.set("waiting for a render thread");
+                    let render_config = config.clone();
+                    let render_name = render_name.clone();
+                    let servable = servable.clone();
+                    let visitor = visitor.clone();
+                    let phase = phase.clone();
+                    tokio::task::spawn_blocking(move || {
+                        phase.set("rendering");
+                        let render = match &servable {
+                            Servable::Advert(advert) => render_location_to_image(&render_name, advert, &visitor, &render_config.encode_buffers)
+                                .map(|(image, format)| (image, content_type(advert, format))),
+                            Servable::Composite(composite) => render_composite(composite, &visitor, &render_config.encode_buffers)
+                                .map(|(image, format)| (image, format.mime_type().to_owned())),
+                        }.map_err(|e| format!("Error encoding PNG: {:?}", e))?;
+                        if let Some(cache) = render_config.render_cache.as_ref().filter(|_| cacheable) {
+                            cache.put(&render_name, &visitor.location, render.clone());
+                        }
+                        Ok(render)
+                    }).await.unwrap_or_else(|e| Err(format!("render thread failed: {:?}", e)))
+                }
+            };
+
+            match render {
+                Ok((image, content_type)) => {
                     // everything worked!
-                    Ok(
-                        Response::builder()
-                            .status(StatusCode::OK)
-                            .header("Content-Type", advert.output_format.mime_type())
-                            .body(image)
-                    )
+                    if config.server.log_hits() {
+                        println!("[{}] hit", iso_string());
+                    }
+
+                    // a country variant might not be hashable even though the advert asked for is
+                    if query.hash.is_some() && !cacheable {
+                        eprintln!("[{}] 404: {} has no content hash URL", iso_string(), image_name);
+                        return Ok(error_response(StatusCode::NOT_FOUND, "resource not found on server", accepts_json));
+                    }
+
+                    let mut response = Response::builder().status(StatusCode::OK);
+                    if config.server.content_hash_urls && cacheable {
+                        let current_hash = content_hash(&image);
+                        let canonical = format!("/ads/{}/{}", image_name, current_hash);
+                        match &query.hash {
+                            // which render is current depends on who's asking, so the redirect itself mustn't be cached
+                            Some(hash) if *hash != current_hash => return Ok(
+                                Response::builder()
+                                    .status(StatusCode::FOUND)
+                                    .header("Location", canonical)
+                                    .header("Cache-Control", "no-store")
+                                    .body(Bytes::new())
+                            ),
+                            Some(_) => response = response.header("Cache-Control", "public, max-age=31536000, immutable"),
+                            None => response = response.header("Link", format!("<{}>; rel=\"canonical\"", canonical)),
+                        }
+                    }
+
+                    if query.wants_multipart() {
+                        let metadata = RenderMetadata::new(render_name, &servable, &visitor, &content_type);
+                        let (content_type, body) = multipart_body(&image, &content_type, &metadata);
+                        return Ok(response.header("Content-Type", content_type).body(Bytes::from(body)));
+                    }
+                    Ok(response.header("Content-Type", content_type).body(image))
                 }
                 Err(e) => {
                     // something went wrong with the the image render
                     eprintln!("[{}] {}", iso_string(), e);
-                    Ok(
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .header("Content-Type", "text/plain")
-                            .body(e.into())
-                    )
+                    Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR, &e, accepts_json))
                 }
             }
         }
         None => {
             // someone requested an image_name that isn't in our config file
             eprintln!("[{}] 404: {}", iso_string(), image_name);
-            Ok(
-                Response::builder()
-                    .status(StatusCode::NOT_FOUND)
-                    .header("Content-Type", "text/plain")
-                    .body("resource not found on server".into())
-            )
+            Ok(error_response(StatusCode::NOT_FOUND, "resource not found on server", accepts_json))
+        }
+    }
+}
+
+/// an error response for the advert endpoints: plain text by default, since the client is most likely an `<img>` tag,
+/// or JSON of the form `{"error": "...", "status": 404}` if the client accepts it
+fn error_response(status: StatusCode, message: &str, json: bool) -> Result<Response<Bytes>, warp::http::Error> {
+    let (content_type, body) = if json {
+        let body = serde_json::json!({
+            "error": message,
+            "status": status.as_u16(),
+        });
+        ("application/json", body.to_string().into_bytes())
+    } else {
+        ("text/plain", message.as_bytes().to_vec())
+    };
+    Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .body(Bytes::from(body))
+}
+
+/// handles a request to any other path, serving the advert configured for it if there is one
+async fn vanity_path_handler(path: FullPath, query: AdvertQuery, config: Arc<Config>, socket_addr: Option<SocketAddr>, headers: ClientHeaders, phase: RequestPhase, host: Option<String>) -> Result<impl warp::Reply, warp::Rejection> {
+    match config.paths.get(path.as_str()) {
+        Some(name) => fake_advert_handler(name.clone(), query, config.clone(), socket_addr, headers, phase, host).await,
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// response body of the /ads/<image_name>/sprite.json endpoint
+#[derive(Serialize)]
+struct SpriteManifest {
+    frames: i32,
+    frame_width: i32,
+    frame_height: i32,
+    /// direction the frames are stacked in. Currently always "vertical".
+    layout: &'static str,
+}
+
+/// handles a request to the /ads/<image_name>/sprite.json endpoint
+async fn sprite_handler(image_name: String, config: Arc<Config>, host: Option<String>) -> Result<warp::reply::Response, warp::Rejection> {
+    match config.adverts.get(&image_name).filter(|_| config.serves(host.as_deref(), &image_name)) {
+        // autocropped frames are sized by what the text says, which differs from visitor to visitor
+        Some(advert) if advert.autocrop.is_some() => {
+            eprintln!("[{}] 404: {}/sprite.json, as its frames are autocropped", iso_string(), image_name);
+            Ok(warp::reply::with_status("resource not found on server", StatusCode::NOT_FOUND).into_response())
+        }
+        Some(advert) => {
+            let manifest = SpriteManifest {
+                frames: advert.frames,
+                frame_width: advert.image_width,
+                frame_height: advert.image_height,
+                layout: "vertical",
+            };
+            Ok(warp::reply::json(&manifest).into_response())
+        }
+        None => {
+            eprintln!("[{}] 404: {}/sprite.json", iso_string(), image_name);
+            Ok(warp::reply::with_status("resource not found on server", StatusCode::NOT_FOUND).into_response())
+        }
+    }
+}
+
+/// a single frame's rectangle within a sprite sheet, in pixels
+#[derive(Serialize)]
+struct FrameRect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    duration_ms: i32,
+}
+
+/// handles a request to the /ads/<image_name>/frames.json endpoint
+async fn frames_handler(image_name: String, config: Arc<Config>, host: Option<String>) -> Result<warp::reply::Response, warp::Rejection> {
+    if !config.server.frame_maps {
+        return Err(warp::reject::not_found());
+    }
+
+    match config.adverts.get(&image_name).filter(|_| config.serves(host.as_deref(), &image_name)) {
+        Some(advert) => {
+            // frames are stacked vertically, top to bottom
+            let frames: Vec<FrameRect> = (0..advert.frames)
+                .map(|frame| FrameRect {
+                    x: 0,
+                    y: frame * advert.image_height,
+                    width: advert.image_width,
+                    height: advert.image_height,
+                    duration_ms: advert.frame_duration_ms,
+                })
+                .collect();
+            Ok(warp::reply::json(&frames).into_response())
+        }
+        None => {
+            eprintln!("[{}] 404: {}/frames.json", iso_string(), image_name);
+            Ok(warp::reply::with_status("resource not found on server", StatusCode::NOT_FOUND).into_response())
+        }
+    }
+}
+
+/// handles a request to the /ads/<image_name>/pixel endpoint, which logs an impression without rendering anything
+async fn pixel_handler(image_name: String, config: Arc<Config>, socket_addr: Option<SocketAddr>, host: Option<String>) -> Result<warp::reply::Response, warp::Rejection> {
+    let exists = config.adverts.contains_key(&image_name) || config.composites.contains_key(&image_name);
+    if !exists || !config.serves(host.as_deref(), &image_name) {
+        eprintln!("[{}] 404: {}/pixel", iso_string(), image_name);
+        return Ok(warp::reply::with_status("resource not found on server", StatusCode::NOT_FOUND).into_response());
+    }
+
+    let location = socket_addr
+        .map(|socket_addr| {
+            let max_accuracy_km = config.adverts.get(&image_name).and_then(|advert| advert.min_accuracy_km);
+            get_city_from_ip(&config.geoip, config.server.location_levels(), max_accuracy_km, socket_addr.ip())
+        })
+        .unwrap_or_else(|| DEFAULT_CITY.to_owned());
+    println!("[{}] impression: {} in {}", iso_string(), image_name, location);
+
+    Ok(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "image/png")
+            // every load needs to reach us to be counted
+            .header("Cache-Control", "no-store")
+            .body(PIXEL.clone().into())
+            .unwrap()
+    )
+}
+
+/// query string for the /geoip endpoint
+#[derive(Deserialize)]
+struct GeoIpQuery {
+    /// address to look up. If absent, the client's own address is used.
+    ip: Option<IpAddr>,
+}
+
+/// handles a request to the /geoip endpoint, which is only available in debug mode
+async fn geoip_handler(query: GeoIpQuery, config: Arc<Config>, socket_addr: Option<SocketAddr>) -> Result<warp::reply::Response, warp::Rejection> {
+    if !config.server.debug {
+        return Err(warp::reject::not_found());
+    }
+
+    if config.geoip.is_empty() {
+        return Ok(warp::reply::with_status("GeoIP is disabled", StatusCode::SERVICE_UNAVAILABLE).into_response());
+    }
+
+    let addr = match query.ip.or(socket_addr.map(|socket_addr| socket_addr.ip())) {
+        Some(addr) => addr,
+        None => return Ok(warp::reply::with_status("no remote address", StatusCode::BAD_REQUEST).into_response()),
+    };
+
+    // like get_city_from_ip, prefer the first database that knows the city, but show a partial record rather than nothing
+    let mut partial: Option<(&GeoIpDatabase, geoip2::City)> = None;
+    let mut error: Option<MaxMindDBError> = None;
+    for database in &config.geoip {
+        match database.reader.lookup::<geoip2::City>(addr) {
+            Ok(city) if city.city.is_some() => return Ok(geoip_record_response(database, &city)),
+            Ok(city) => {
+                partial.get_or_insert((database, city));
+            }
+            Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+            Err(e) => {
+                eprintln!("[{}] GeoIP lookup of {} in {} failed: {:?}", iso_string(), addr, database.path, e);
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    match (partial, error) {
+        (Some((database, city)), _) => Ok(geoip_record_response(database, &city)),
+        (None, Some(e)) => {
+            Ok(warp::reply::with_status(format!("GeoIP lookup failed: {:?}", e), StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+        (None, None) => {
+            Ok(warp::reply::with_status(format!("no GeoIP record for {}", addr), StatusCode::NOT_FOUND).into_response())
         }
     }
 }
 
-/// get an approximate city from an IP address, falling back to a default on failure
-fn get_city_from_ip(addr: IpAddr) -> String {
-    (*GEOIP).lookup(addr).ok()
-        .and_then(|city: geoip2::City| city.city)
-        .and_then(|city| city.names)
-        .and_then(|names| names.iter().next().map(|(_k, v)| v.to_owned()))
-        .map(|v| v.to_owned())
+/// query string for the /bundle endpoint
+#[derive(Deserialize)]
+struct BundleQuery {
+    /// location to render every advert for, exactly as it would appear in the text
+    city: String,
+}
+
+/// handles a request to the /bundle endpoint, which is only available in debug mode
+async fn bundle_handler(query: BundleQuery, config: Arc<Config>, socket_addr: Option<SocketAddr>, user_agent: Option<String>) -> Result<warp::reply::Response, warp::Rejection> {
+    if !config.server.debug {
+        return Err(warp::reject::not_found());
+    }
+
+    let socket_addr = match socket_addr {
+        Some(socket_addr) => socket_addr,
+        None => return Ok(warp::reply::with_status("no remote address", StatusCode::BAD_REQUEST).into_response()),
+    };
+
+    // the permit goes to the render thread, so it's only given back once the bundle is finished, even if the request
+    // times out first
+    let permit = match config.bundle_permits.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            let reply = warp::reply::with_status("another bundle is being built", StatusCode::SERVICE_UNAVAILABLE);
+            return Ok(warp::reply::with_header(reply, "Retry-After", "1").into_response());
+        }
+    };
+
+    // a bundle is a lot of renders, so it counts against the render throttle like any other
+    if let Some(throttle) = &config.throttle {
+        if let Err(wait) = throttle.try_render(socket_addr.ip()) {
+            let reply = warp::reply::with_status("too many requests", StatusCode::TOO_MANY_REQUESTS);
+            return Ok(warp::reply::with_header(reply, "Retry-After", wait.as_secs_f64().ceil().to_string()).into_response());
+        }
+    }
+
+    let bundle = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let (browser, os) = get_browser_and_os(user_agent.as_deref());
+        let visitor = Visitor {
+            ip: socket_addr.ip(),
+            location: query.city,
+            browser,
+            os,
+            country: None,
+        };
+        build_bundle(&config, &visitor)
+    }).await.unwrap_or_else(|e| Err(format!("render thread failed: {:?}", e)));
+
+    match bundle {
+        Ok(bundle) => Ok(
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/gzip")
+                .header("Content-Disposition", "attachment; filename=\"adverts.tar.gz\"")
+                .header("X-Bundle-Skipped", bundle.skipped.to_string())
+                .body(bundle.archive.into())
+                .unwrap()
+        ),
+        Err(e) => {
+            eprintln!("[{}] {}", iso_string(), e);
+            Ok(warp::reply::with_status(e, StatusCode::INTERNAL_SERVER_ERROR).into_response())
+        }
+    }
+}
+
+/// a GeoIP record as JSON, with a header naming the database it came from
+fn geoip_record_response(database: &GeoIpDatabase, city: &geoip2::City) -> warp::reply::Response {
+    warp::reply::with_header(warp::reply::json(city), "X-GeoIP-Database", database.path.clone()).into_response()
+}
+
+/// get an approximate location from an IP address, falling back to a default if no database knows it (or GeoIP is
+/// disabled). Each level is tried in every database before moving on to the next level. If `max_accuracy_km` is set,
+/// records that can't place the address at least that precisely are ignored, as are records that don't say.
+fn get_city_from_ip(databases: &[GeoIpDatabase], levels: &[LocationLevel], max_accuracy_km: Option<u32>, addr: IpAddr) -> String {
+    city_from_records(&lookup_records(databases, addr), levels, max_accuracy_km)
+}
+
+/// the records of every database that knows an IP address, in lookup order
+fn lookup_records(databases: &[GeoIpDatabase], addr: IpAddr) -> Vec<geoip2::City<'_>> {
+    databases.iter()
+        .filter_map(|database| lookup_city(database, addr))
+        .collect()
+}
+
+/// get_city_from_ip, for records that have already been looked up
+fn city_from_records(records: &[geoip2::City], levels: &[LocationLevel], max_accuracy_km: Option<u32>) -> String {
+    let records: Vec<&geoip2::City> = records.iter()
+        .filter(|record| max_accuracy_km.is_none_or(|max_accuracy_km| {
+            record.location.as_ref()
+                .and_then(|location| location.accuracy_radius)
+                .is_some_and(|radius| u32::from(radius) <= max_accuracy_km)
+        }))
+        .collect();
+    levels.iter()
+        .find_map(|level| records.iter().find_map(|record| location_name(record, *level)))
         .unwrap_or_else(|| DEFAULT_CITY.to_owned())
 }
 
+/// the name a GeoIP record gives for one level of a location, if it has one
+fn location_name(record: &geoip2::City, level: LocationLevel) -> Option<String> {
+    let names = match level {
+        LocationLevel::City => record.city.as_ref().and_then(|city| city.names.as_ref()),
+        // subdivisions are listed from largest to smallest
+        LocationLevel::Subdivision => record.subdivisions.as_ref()
+            .and_then(|subdivisions| subdivisions.first())
+            .and_then(|subdivision| subdivision.names.as_ref()),
+        LocationLevel::Country => record.country.as_ref().and_then(|country| country.names.as_ref()),
+    };
+    names.and_then(|names| names.iter().next().map(|(_k, v)| (*v).to_owned()))
+}
+
+/// look an IP address up in a single database. Addresses it has no record of are expected and pass quietly, but any
+/// other error means something is wrong with the database itself, so it gets logged.
+fn lookup_city(database: &GeoIpDatabase, addr: IpAddr) -> Option<geoip2::City<'_>> {
+    match database.reader.lookup::<geoip2::City>(addr) {
+        Ok(city) => Some(city),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+        Err(e) => {
+            eprintln!("[{}] WARNING: GeoIP lookup of {} in {} failed: {:?}", iso_string(), addr, database.path, e);
+            None
+        }
+    }
+}
+
+/// the ISO code of the country the first record that knows it puts an IP address in
+fn country_from_records(records: &[geoip2::City]) -> Option<String> {
+    records.iter()
+        .filter_map(|city| city.country.as_ref())
+        .find_map(|country| country.iso_code.map(|iso_code| iso_code.to_uppercase()))
+}
+
+/// get a browser and operating system name from a User-Agent, falling back to defaults if it's missing or unrecognized
+fn get_browser_and_os(user_agent: Option<&str>) -> (String, String) {
+    let result = user_agent.and_then(|user_agent| woothee::parser::Parser::new().parse(user_agent));
+    let known = |value: &str| value != woothee::woothee::VALUE_UNKNOWN;
+    let browser = result.as_ref()
+        .map(|result| result.name)
+        .filter(|name| known(name))
+        .unwrap_or(DEFAULT_BROWSER);
+    let os = result.as_ref()
+        .map(|result| result.os)
+        .filter(|os| known(os))
+        .unwrap_or(DEFAULT_OS);
+    (browser.to_owned(), os.to_owned())
+}
+
+/// the Content-Type to serve an advert with, given the format it actually got encoded in
+fn content_type(advert: &Advert, format: &ImageOutput) -> String {
+    advert.content_type_override.clone()
+        .unwrap_or_else(|| format.mime_type().to_owned())
+}
+
+/// render every advert that can be precached for the fallback location, by name
+fn precache_default_city(config: &Config) -> HashMap<String, Render> {
+    let visitor = Visitor {
+        ip: IpAddr::from([0, 0, 0, 0]),
+        location: DEFAULT_CITY.to_owned(),
+        browser: DEFAULT_BROWSER.to_owned(),
+        os: DEFAULT_OS.to_owned(),
+        country: None,
+    };
+    let mut renders = HashMap::new();
+    for (name, advert) in &config.adverts {
+        if !advert.only_varies_by_location() {
+            println!("[{}] Not precaching \"{}\", as it varies by more than location", iso_string(), name);
+            continue;
+        }
+        match render_location_to_image(name, advert, &visitor, &config.encode_buffers) {
+            Ok((image, format)) => {
+                renders.insert(name.clone(), (image, content_type(advert, format)));
+            }
+            Err(e) => eprintln!("[{}] WARNING: failed to precache \"{}\": {}", iso_string(), name, e),
+        }
+    }
+    println!("[{}] Precached {} adverts for \"{}\"", iso_string(), renders.len(), DEFAULT_CITY);
+    renders
+}
+
 /// render some custom text over an image, where that custom text contains a location (e.g. "singles near New York City")
-fn render_location_to_image(advert: &Advert, location: String) -> Result<Vec<u8>, String> {
+fn render_location_to_image<'a>(name: &str, advert: &'a Advert, visitor: &Visitor, pool: &BufferPool) -> Result<(Bytes, &'a ImageOutput), String> {
+    let mut image = render_advert(name, advert, visitor)?;
+    let mut animation = advert.animation();
+    if let Some(rect) = autocrop_rect(advert, visitor) {
+        image = crop_frames(advert, &image, rect);
+        (_, _, animation.frame_width, animation.frame_height) = rect;
+    }
+    let (mut buffer, format) = encode_image(&image, &advert.output_format, &advert.fallback_formats, &animation, pool)?;
+    if let Some(dpi) = advert.dpi {
+        buffer = set_dpi(buffer, format, dpi);
+    }
+    if advert.write_metadata {
+        buffer = embed_metadata(buffer, format, name, &visitor.location);
+    }
+    Ok((pool.finish(buffer), format))
+}
+
+/// render each member of a composite for the same visitor, then stitch them into a single image
+fn render_composite<'a>(composite: &'a Composite, visitor: &Visitor, pool: &BufferPool) -> Result<(Bytes, &'a ImageOutput), String> {
+    let images = composite.members.iter()
+        .map(|(name, advert)| render_advert(name, advert, visitor))
+        .collect::<Result<Vec<_>, _>>()?;
+    let image = composite.stitch(&images);
+    let (buffer, format) = encode_image(&image, &composite.output_format, &composite.fallback_formats, &composite.animation, pool)?;
+    Ok((pool.finish(buffer), format))
+}
+
+/// draw everything an advert has onto a fresh copy of its image, ready to be encoded
+fn render_advert(name: &str, advert: &Advert, visitor: &Visitor) -> Result<DynamicImage, String> {
+    let location = &visitor.location;
+
     // we need a fresh copy of the image to render to
-    let mut image = advert.image.clone();
+    let base_image = advert.image_for(location, visitor.country.as_deref());
+    let mut image = base_image.clone();
 
     // grab a bunch of fields out of the config just for ease of use later
     let image_width = advert.image_width;
     let image_height = advert.image_height;
-    let text_x = advert.text_x;
-    let text_y = advert.text_y;
-    let text_scale = advert.text_scale;
+    let mut style = text_style(advert);
+
+    // draw the QR code, which gets the location before any case changes
+    if let Some(qr) = &advert.qr {
+        let content = fill_template(&qr.content, visitor);
+        for frame in 0..advert.frames {
+            draw_qr(&mut image, &content, qr.x, qr.y + frame * image_height, qr.size)?;
+        }
+    }
+
+    if let Some(meter) = &advert.meter {
+        let fill = meter.fill_for(&advert.ip_hash_key, visitor.ip);
+        for frame in 0..advert.frames {
+            draw_meter(&mut image, meter, fill, frame * image_height);
+        }
+    }
+
+    if let Some(bar) = &advert.bar {
+        for frame in 0..advert.frames {
+            draw_progress_bar(&mut image, bar, frame, advert.frames, frame * image_height);
+        }
+    }
+
+    let TextLayout { text, x, y: text_y, width: text_width } = layout_text(advert, visitor, &style);
+
+    // some special logging for the edge case where the text renders off the side of the image
+    let overflow = (x + text_width) - image_width;
+    if overflow > 0 {
+        println!("[{}] \"{}\" overflowed by {}px", iso_string(), name, overflow);
+    }
+
+    // render the text
+    for frame in 0..advert.frames {
+        let y = text_y + frame * image_height;
+        if let Some(wave) = &mut style.wave {
+            // one full cycle over the animation, so it loops smoothly
+            wave.phase = std::f32::consts::TAU * frame as f32 / advert.frames as f32;
+        }
+        let (top, text_height) = ink_band(&style, &text, y);
+        if let Some((dark, light)) = advert.auto_contrast {
+            // judge the background from the untouched image, so a QR code or meter under the text doesn't sway it
+            style.color = contrasting_color(base_image, x, top, text_width as u32, text_height, dark, light);
+        }
+        match advert.min_contrast_ratio {
+            Some(min_ratio) => {
+                let region = text_region(&image, x, top, text_width as u32, text_height);
+                let before = region.map(|(left, top, width, height)| image.crop_imm(left, top, width, height));
+                draw_text(&mut image, &style, x, y, &text);
+                if let (Some((left, top, width, height)), Some(before)) = (region, before) {
+                    let after = image.crop_imm(left, top, width, height);
+                    if let Some(ratio) = contrast_ratio(&before, &after) {
+                        if ratio < min_ratio {
+                            eprintln!("[{}] low contrast in \"{}\" frame {}: {:.2}:1 is below {}:1 for \"{}\"", iso_string(), name, frame, ratio, min_ratio, text);
+                        }
+                    }
+                }
+            }
+            None => draw_text(&mut image, &style, x, y, &text),
+        }
+    }
+
+    if let Some((width, top, bottom)) = advert.border {
+        for frame in 0..advert.frames {
+            let rect = RoundedRect::new(0.0, (frame * image_height) as f32, image_width as f32, image_height as f32, 0.0);
+            stroke_rect_gradient(&mut image, &rect, width as f32, top, bottom);
+        }
+    }
+
+    if let Some((color, include_ip)) = advert.watermark {
+        draw_watermark(&mut image, advert, color, include_ip.then_some(visitor.ip));
+    }
+
+    // clip to the mask's shape. Formats without alpha flatten what's clipped away onto white.
+    if let Some(mask) = &advert.mask {
+        let mut rgba = image.into_rgba8();
+        let mask_height = mask.height();
+        for (x, y, pixel) in rgba.enumerate_pixels_mut() {
+            let coverage = mask.get_pixel(x, y % mask_height)[0];
+            pixel[3] = (u16::from(pixel[3]) * u16::from(coverage) / u16::from(u8::MAX)) as u8;
+        }
+        image = rgba.into();
+    }
+
+    // harden soft alpha edges into a clean cutout
+    if let Some(threshold) = advert.alpha_threshold {
+        if advert.output_format.has_alpha() && image.color().has_alpha() {
+            let mut rgba = image.into_rgba8();
+            for pixel in rgba.pixels_mut() {
+                pixel[3] = if pixel[3] < threshold { 0 } else { u8::MAX };
+            }
+            image = rgba.into();
+        }
+    }
+
+    Ok(image)
+}
+
+/// how an advert's text is drawn, before any per-frame changes
+fn text_style(advert: &Advert) -> TextStyle {
+    TextStyle {
+        scale: advert.text_scale,
+        color: advert.text_color,
+        snap_baseline: advert.snap_baseline,
+        anchor_baseline: advert.text_anchor_baseline,
+        wave: advert.wave.map(|(amplitude, frequency)| Wave {
+            amplitude,
+            frequency,
+            phase: 0.0,
+        }),
+    }
+}
 
+/// what an advert's text says for a visitor, and where it goes
+struct TextLayout {
+    text: String,
+    /// left of the text
+    x: i32,
+    /// where the text goes vertically within a frame, measured like text_y
+    y: i32,
+    width: i32,
+}
+
+/// work out what an advert's text says for a visitor, and where it goes
+fn layout_text(advert: &Advert, visitor: &Visitor, style: &TextStyle) -> TextLayout {
     // handle the desired text case
-    let location: String = match advert.text_case {
-        Case::Default => location,
-        Case::Upper => location.to_uppercase()
+    let mut display_location: String = match advert.text_case {
+        Case::Default => visitor.location.clone(),
+        Case::Upper => visitor.location.to_uppercase()
     };
+    for (from, to) in &advert.location_substitutions {
+        display_location = display_location.replace(from.as_str(), to);
+    }
 
     // figure out how wide the text is
-    let text: String = format!("{}{}", advert.text_prefix, location);
-    let (text_width, _text_height): (u32, _) = text_size(text_scale, &*FONT, &text);
-    let text_width: i32 = text_width.try_into().unwrap();
+    let text: String = format!("{}{}", fill_template(&advert.text_prefix, visitor), display_location);
+    let (width, _): (u32, u32) = text_size(style, &text);
+    let width: i32 = width.try_into().unwrap();
 
     // calculate x coordinate if we're centering the text
     let x = match advert.text_align {
-        Align::Left => text_x,
-        Align::Center => text_x.checked_sub(text_width / 2).unwrap_or(0),
+        Align::Left => advert.text_x,
+        Align::Center => advert.text_x.checked_sub(width / 2).unwrap_or(0),
     };
 
-    // some special logging for the edge case where the text renders off the side of the image
-    if x + text_width > image_width {
-        let overflow = (x + text_width) - image_width;
-        println!("[{}] hit, overflowed by {}px", iso_string(), overflow);
-    } else {
-        println!("[{}] hit", iso_string());
+    let y = match (advert.keep_out, text_bounds(advert, style, &text)) {
+        (Some(keep_out), Some(bounds)) => avoid_keep_out(advert, keep_out, x, bounds),
+        _ => advert.text_y,
+    };
+
+    TextLayout { text, x, y, width }
+}
+
+/// move text out of the way of a keep-out rectangle, if it's in the way: to just above it or just below it, whichever
+/// is closer to where the text would have gone and still fits in the frame. If neither fits, it stays put.
+fn avoid_keep_out(advert: &Advert, (left, top, width, height): (i32, i32, i32, i32), x: i32, (ink_left, ink_top, ink_right, ink_bottom): (i32, i32, i32, i32)) -> i32 {
+    let text_top = advert.text_y + ink_top;
+    let text_height = ink_bottom - ink_top;
+
+    let overlaps = x + ink_left < left + width && left < x + ink_right && text_top < top + height && top < text_top + text_height;
+    if !overlaps {
+        return advert.text_y;
     }
 
-    // render the text
+    // candidates for where the top of the ink goes, which is converted back to text_y's reckoning at the end
+    let above = top - KEEP_OUT_GAP - text_height;
+    let below = top + height + KEEP_OUT_GAP;
+    [above, below].into_iter()
+        .filter(|&candidate| candidate >= 0 && candidate + text_height <= advert.image_height)
+        .min_by_key(|&candidate| (candidate - text_top).abs())
+        .map_or(advert.text_y, |candidate| candidate - ink_top)
+}
+
+/// where the ink of an advert's text can reach relative to where it's drawn, as left, top, right, and bottom. This
+/// covers ascenders and descenders wherever the baseline is, and the whole swing of any wave. None if nothing is drawn.
+fn text_bounds(advert: &Advert, style: &TextStyle, text: &str) -> Option<(i32, i32, i32, i32)> {
+    let (left, top, right, bottom) = text_extent(style, text)?;
+    let swing = advert.wave.map_or(0, |(amplitude, _)| amplitude.abs().ceil() as i32);
+    Some((left, top - swing, right, bottom + swing))
+}
+
+/// the part of each frame autocrop_to_text keeps for a visitor: the text plus a margin, as left, top, width, and height.
+/// None if the advert isn't cropped, or if the text is entirely outside the frame and there'd be nothing left.
+fn autocrop_rect(advert: &Advert, visitor: &Visitor) -> Option<(u32, u32, u32, u32)> {
+    let margin = advert.autocrop?;
+    let style = text_style(advert);
+    let TextLayout { text, x, y, .. } = layout_text(advert, visitor, &style);
+    let (ink_left, ink_top, ink_right, ink_bottom) = text_bounds(advert, &style, &text)?;
+
+    let left = (x + ink_left - margin).clamp(0, advert.image_width);
+    let right = (x + ink_right + margin).clamp(0, advert.image_width);
+    let top = (y + ink_top - margin).clamp(0, advert.image_height);
+    let bottom = (y + ink_bottom + margin).clamp(0, advert.image_height);
+    (right > left && bottom > top).then(|| (left as u32, top as u32, (right - left) as u32, (bottom - top) as u32))
+}
+
+/// the width and height of each frame of an advert's output for a visitor, which autocrop_to_text can make smaller
+fn frame_size(advert: &Advert, visitor: &Visitor) -> (i32, i32) {
+    autocrop_rect(advert, visitor).map_or((advert.image_width, advert.image_height), |(_, _, width, height)| (width as i32, height as i32))
+}
+
+/// cut the same rectangle out of every frame, and stack the pieces back into a sprite sheet
+fn crop_frames(advert: &Advert, image: &DynamicImage, (left, top, width, height): (u32, u32, u32, u32)) -> DynamicImage {
+    let mut sheet = DynamicImage::new(width, height * advert.frames as u32, image.color());
+    for frame in 0..advert.frames as u32 {
+        let piece = image.crop_imm(left, top + frame * advert.image_height as u32, width, height);
+        sheet.copy_from(&piece, 0, frame * height).expect("cropped frame should fit in the cropped sheet");
+    }
+    sheet
+}
+
+/// the top and height of the band that text drawn at y covers, going by its ink, so it's right whether y is the top or
+/// the baseline. Empty if nothing would be drawn.
+fn ink_band(style: &TextStyle, text: &str, y: i32) -> (i32, u32) {
+    text_extent(style, text).map_or((y, 0), |(_, top, _, bottom)| (y + top, (bottom - top) as u32))
+}
+
+/// clip a rectangle to the bounds of an image, giving its left, top, width, and height, or None if nothing is left
+fn text_region(image: &DynamicImage, x: i32, y: i32, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+    let left = x.clamp(0, image.width() as i32) as u32;
+    let top = y.clamp(0, image.height() as i32) as u32;
+    let right = (x + width as i32).clamp(0, image.width() as i32) as u32;
+    let bottom = (y + height as i32).clamp(0, image.height() as i32) as u32;
+    (right > left && bottom > top).then(|| (left, top, right - left, bottom - top))
+}
+
+/// draw the current time, and optionally a hash of the client's IP, faintly into the bottom right corner of each frame
+fn draw_watermark(image: &mut DynamicImage, advert: &Advert, color: Rgba<u8>, ip: Option<IpAddr>) {
+    let time = if advert.deterministic { DateTime::UNIX_EPOCH } else { Utc::now() };
+    let mut text = time.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    if let Some(ip) = ip {
+        text.push_str(&format!(" {:08x}", ip_hash(&advert.ip_hash_key, ip) as u32));
+    }
+
+    let style = TextStyle {
+        scale: PxScale::from(WATERMARK_SCALE),
+        color,
+        snap_baseline: true,
+        anchor_baseline: false,
+        wave: None,
+    };
+    let (width, height) = text_size(&style, &text);
+    let x = advert.image_width - width as i32 - WATERMARK_MARGIN;
     for frame in 0..advert.frames {
-        let y = text_y + frame * image_height;
-        draw_text_mut(&mut image, advert.text_color, x, y, text_scale, &*FONT, &text);
+        let y = (frame + 1) * advert.image_height - height as i32 - WATERMARK_MARGIN;
+        draw_text(image, &style, x, y, &text);
+    }
+}
+
+/// encode a finished image in the given output format, trying the fallback formats in order if that fails. The image is
+/// encoded into a buffer from the pool, which the caller should [BufferPool::finish] once it's done with it.
+fn encode_image<'a>(image: &DynamicImage, output_format: &'a ImageOutput, fallback_formats: &'a [ImageOutput], animation: &Animation, pool: &BufferPool) -> Result<(Vec<u8>, &'a ImageOutput), String> {
+    let mut buffer: Vec<u8> = pool.take();
+    let mut errors: Vec<String> = Vec::new();
+    let mut flattened: Option<DynamicImage> = None;
+    let mut narrowed: Option<DynamicImage> = None;
+    for format in std::iter::once(output_format).chain(fallback_formats) {
+        buffer.clear();
+        let image = if !format.has_alpha() && image.color().has_alpha() {
+            flattened.get_or_insert_with(|| flatten(image))
+        } else if *format == ImageOutput::Webp && !matches!(image.color(), ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8) {
+            // the WebP encoder only takes 8 bits per channel
+            narrowed.get_or_insert_with(|| image.to_rgba8().into())
+        } else {
+            image
+        };
+        let result = match format {
+            ImageOutput::AnimatedWebp => encode_animated_webp(image, animation, &mut buffer),
+            _ => image.write_to(&mut Cursor::new(&mut buffer), format.format()).map_err(|e| format!("{:?}", e)),
+        };
+        match result {
+            Ok(()) => {
+                if !errors.is_empty() {
+                    eprintln!("[{}] fell back to {:?} output: {}", iso_string(), format, errors.join(", "));
+                }
+                return Ok((buffer, format));
+            }
+            Err(e) => errors.push(format!("failed to encode output image as {:?}: {}", format, e)),
+        }
+    }
+    pool.give(buffer);
+    Err(errors.join(", "))
+}
+
+/// composite an image with transparency over white, for output formats that can't store alpha
+fn flatten(image: &DynamicImage) -> DynamicImage {
+    let mut rgb = RgbImage::new(image.width(), image.height());
+    for (out, pixel) in rgb.pixels_mut().zip(image.to_rgba8().pixels()) {
+        let alpha = u16::from(pixel[3]);
+        for channel in 0..3 {
+            out[channel] = ((u16::from(pixel[channel]) * alpha + 255 * (255 - alpha)) / 255) as u8;
+        }
+    }
+    rgb.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use std::num::NonZeroUsize;
+
+    use image::{GrayImage, ImageBuffer, Luma, Rgba};
+    use tokio::sync::Semaphore;
+
+    use crate::cache::RenderCache;
+
+    use super::*;
+
+    fn animation(image: &DynamicImage) -> Animation {
+        Animation { frame_width: image.width(), frame_height: image.height(), frames: 1, frame_duration_ms: 100 }
+    }
+
+    /// encode as WebP, check it's a WebP container, and decode it again
+    fn webp_round_trip(image: &DynamicImage) -> DynamicImage {
+        let (encoded, format) = encode_image(image, &ImageOutput::Webp, &[], &animation(image), &BufferPool::new(0)).unwrap();
+        assert_eq!(*format, ImageOutput::Webp);
+        assert_eq!(&encoded[..4], b"RIFF");
+        assert_eq!(&encoded[8..12], b"WEBP");
+        image::load_from_memory_with_format(&encoded, ImageFormat::WebP).unwrap()
+    }
+
+    #[test]
+    fn webp_output_is_lossless() {
+        let image: DynamicImage = RgbaImage::from_fn(16, 8, |x, y| Rgba([(x * 16) as u8, (y * 32) as u8, 128, (x * y) as u8 + 100])).into();
+        assert_eq!(webp_round_trip(&image).to_rgba8(), image.to_rgba8());
+    }
+
+    #[test]
+    fn webp_output_takes_16_bit_images() {
+        let image: DynamicImage = ImageBuffer::from_pixel(16, 8, Rgba([0xFFFFu16, 0x8080, 0, 0xFFFF])).into();
+        assert_eq!(webp_round_trip(&image).to_rgba8(), image.to_rgba8());
+    }
+
+    /// a config serving one advert, "hot.png": a 640x360 PNG saying where the visitor is. Nothing is cached.
+    fn test_config() -> Config {
+        let server = ServerDefinition::default();
+        let definition: AdvertDefinition = toml::from_str(r#"
+            image_width = 640
+            image_height = 360
+            frames = 1
+            text_x = 320
+            text_y = 160
+            text_color = [240, 255, 255, 255]
+            text_scale = 40.0
+            text_align = "Center"
+            text_case = "Default"
+            output_format = "Png"
+            text_prefix = "Singles in "
+        "#).unwrap();
+        let advert = Advert::open("hot.png", definition, &server);
+        Config {
+            server,
+            adverts: HashMap::from([("hot.png".to_owned(), Arc::new(advert))]),
+            composites: HashMap::new(),
+            paths: HashMap::new(),
+            encode_buffers: BufferPool::new(0),
+            throttle: None,
+            geoip: Vec::new(),
+            default_renders: HashMap::new(),
+            render_cache: None,
+            bundle_permits: Arc::new(Semaphore::new(1)),
+        }
     }
 
-    // encode the image
-    let mut buffer: Vec<u8> = Vec::new();
-    image.write_to(&mut Cursor::new(&mut buffer), advert.output_format.format())
-        .map_err(|e| format!("failed to encode output image: {:?}", e))?;
-    Ok(buffer)
+    /// request "hot.png" the way a browser would. With no GeoIP databases, everyone is in the fallback location.
+    async fn request_advert(config: &Arc<Config>) -> warp::reply::Response {
+        let query = AdvertQuery { placeholder: None, multipart: None, hash: None };
+        let headers = ClientHeaders { user_agent: Some("test".to_owned()), accepts_json: false };
+        let socket_addr = Some(SocketAddr::from(([192, 0, 2, 1], 1234)));
+        fake_advert_handler("hot.png".to_owned(), query, config.clone(), socket_addr, headers, RequestPhase::default(), None).await
+            .unwrap()
+            .into_response()
+    }
+
+    #[test]
+    fn cached_renders_are_served_while_every_render_thread_is_busy() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            // tie up the only render thread with a render that won't finish until we say so
+            let (finish, stalled) = std::sync::mpsc::channel::<()>();
+            let slow_render = tokio::task::spawn_blocking(move || stalled.recv());
+
+            let mut config = test_config();
+            config.default_renders.insert("hot.png".to_owned(), (Bytes::from_static(b"cached render"), "image/png".to_owned()));
+            let response = tokio::time::timeout(Duration::from_secs(5), request_advert(&Arc::new(config))).await
+                .expect("the cached render waited for a render thread");
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "cached render");
+
+            finish.send(()).unwrap();
+            slow_render.await.unwrap().unwrap();
+        });
+    }
+
+    /// save an image where an advert definition can point to it, returning its path
+    fn save_test_image(file_name: &str, image: DynamicImage) -> String {
+        let path = std::env::temp_dir().join(format!("singles-in-your-area-{}-{}", std::process::id(), file_name));
+        image.save(&path).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    /// a 64x32 advert whose image is actually 80x40, which image_size_mismatch = "Warn" (the default) lets through,
+    /// masked by the given mask
+    fn open_mismatched_masked_advert(mask_width: u32, mask_height: u32) -> Advert {
+        let image = save_test_image("mismatched.png", RgbaImage::from_pixel(80, 40, Rgba([255, 0, 0, 255])).into());
+        let mask = save_test_image(&format!("mask-{}x{}.png", mask_width, mask_height), GrayImage::from_pixel(mask_width, mask_height, Luma([128])).into());
+        let definition: AdvertDefinition = toml::from_str(&format!(r#"
+            image = "{}"
+            mask_image = "{}"
+            image_width = 64
+            image_height = 32
+            frames = 1
+            text_x = 0
+            text_y = 0
+            text_color = [0, 0, 0, 255]
+            text_scale = 12.0
+            text_align = "Left"
+            text_case = "Default"
+            output_format = "Png"
+            text_prefix = "Singles in "
+        "#, image, mask)).unwrap();
+        Advert::open("masked.png", definition, &ServerDefinition::default())
+    }
+
+    #[test]
+    fn masks_fit_the_image_even_if_it_is_not_the_configured_size() {
+        let advert = open_mismatched_masked_advert(80, 40);
+        let visitor = Visitor {
+            ip: IpAddr::from([192, 0, 2, 1]),
+            location: DEFAULT_CITY.to_owned(),
+            browser: "test".to_owned(),
+            os: "test".to_owned(),
+            country: None,
+        };
+        let (encoded, _) = render_location_to_image("masked.png", &advert, &visitor, &BufferPool::new(0)).unwrap();
+        let rendered = image::load_from_memory(&encoded).unwrap().to_rgba8();
+        assert_eq!(rendered.dimensions(), (80, 40));
+        assert_eq!(rendered.get_pixel(79, 39)[3], 128);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be 80x40 (one frame of its image)")]
+    fn masks_sized_to_the_config_but_not_the_image_are_refused() {
+        open_mismatched_masked_advert(64, 32);
+    }
+
+    #[test]
+    fn repeat_requests_are_served_from_the_render_cache() {
+        let mut config = test_config();
+        config.render_cache = NonZeroUsize::new(1).map(RenderCache::new);
+        let config = Arc::new(config);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let first = hyper::body::to_bytes(request_advert(&config).await.into_body()).await.unwrap();
+            let (cached, content_type) = config.render_cache.as_ref().unwrap().get("hot.png", DEFAULT_CITY)
+                .expect("the first render should have been cached");
+            assert_eq!(cached, first);
+            assert_eq!(content_type, "image/png");
+
+            // a fresh render would be encoded into a new buffer, but the cached one is shared rather than copied
+            let second = hyper::body::to_bytes(request_advert(&config).await.into_body()).await.unwrap();
+            assert_eq!(second.as_ptr(), cached.as_ptr());
+        });
+    }
+
+    /// a benchmark of the render cache, timing repeat requests for the same advert and place with it off and then on.
+    /// Run `cargo test --release render_cache_speeds_up -- --ignored --nocapture` to see the timings.
+    #[test]
+    #[ignore = "a benchmark, which only means anything in a release build on a quiet machine"]
+    fn render_cache_speeds_up_repeat_requests() {
+        const REQUESTS: u32 = 20;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let time_requests = |config: Config| {
+            let config = Arc::new(config);
+            runtime.block_on(async {
+                // the first request fills the cache, if there is one
+                request_advert(&config).await;
+                let start = Instant::now();
+                for _ in 0..REQUESTS {
+                    assert_eq!(request_advert(&config).await.status(), StatusCode::OK);
+                }
+                start.elapsed() / REQUESTS
+            })
+        };
+
+        let uncached = time_requests(test_config());
+        let mut config = test_config();
+        config.render_cache = NonZeroUsize::new(1).map(RenderCache::new);
+        let cached = time_requests(config);
+        println!("a repeat request took {:?} without the render cache, and {:?} with it", uncached, cached);
+    }
 }
@@ -0,0 +1,157 @@
+use crate::advert::ImageOutput;
+
+const PNG_SIGNATURE_LEN: usize = 8;
+const JPEG_SOI_LEN: usize = 2;
+const JPEG_APP0: [u8; 2] = [0xFF, 0xE0];
+const JPEG_APP1: [u8; 2] = [0xFF, 0xE1];
+
+const JFIF_IDENTIFIER: &[u8; 5] = b"JFIF\0";
+/// offset of the density units field from the start of the APP0 marker
+const JFIF_UNITS_OFFSET: usize = 11;
+const JFIF_UNIT_DPI: u8 = 1;
+const PNG_UNIT_METER: u8 = 1;
+const METERS_PER_INCH: f64 = 0.0254;
+
+const EXIF_TAG_DOCUMENT_NAME: u16 = 0x010D;
+const EXIF_TAG_IMAGE_DESCRIPTION: u16 = 0x010E;
+const EXIF_TYPE_ASCII: u16 = 2;
+
+/// add the advert name and resolved location to an already encoded image:
+/// as iTXt chunks for PNG, or as EXIF DocumentName and ImageDescription for JPEG
+pub fn embed_metadata(image: Vec<u8>, format: &ImageOutput, advert_name: &str, location: &str) -> Vec<u8> {
+    match format {
+        ImageOutput::Png => {
+            let mut chunks = png_itxt_chunk("Advert", advert_name);
+            chunks.extend(png_itxt_chunk("Location", location));
+            insert_png_chunks(image, &chunks)
+        }
+        ImageOutput::Jpeg => {
+            let exif = exif_segment(&[
+                (EXIF_TAG_DOCUMENT_NAME, advert_name),
+                (EXIF_TAG_IMAGE_DESCRIPTION, location),
+            ]);
+            insert_jpeg_segment(image, &exif)
+        }
+        // WebP metadata lives in a RIFF container we'd have to rebuild, so it's not supported yet
+        ImageOutput::Webp | ImageOutput::AnimatedWebp => image,
+    }
+}
+
+/// set the physical resolution of an already encoded image, so it prints at the intended size:
+/// with a pHYs chunk for PNG, or the density fields of the JFIF header for JPEG
+pub fn set_dpi(image: Vec<u8>, format: &ImageOutput, dpi: u16) -> Vec<u8> {
+    match format {
+        ImageOutput::Png => {
+            // PNG only knows pixels per meter
+            let pixels_per_meter = (f64::from(dpi) / METERS_PER_INCH).round() as u32;
+            let mut data = Vec::with_capacity(9);
+            data.extend_from_slice(&pixels_per_meter.to_be_bytes());
+            data.extend_from_slice(&pixels_per_meter.to_be_bytes());
+            data.push(PNG_UNIT_METER);
+            insert_png_chunks(image, &png_chunk(b"pHYs", &data))
+        }
+        ImageOutput::Jpeg => set_jfif_density(image, dpi),
+        // WebP has no resolution field outside of EXIF, which we don't write for it
+        ImageOutput::Webp | ImageOutput::AnimatedWebp => image,
+    }
+}
+
+/// build a complete PNG chunk: length, type, data, and CRC
+fn png_chunk(chunk_type: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(chunk_type);
+    chunk.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+/// an uncompressed iTXt chunk, which unlike tEXt can hold any UTF-8 (city names are rarely pure Latin-1)
+fn png_itxt_chunk(keyword: &str, text: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(keyword.as_bytes());
+    // null separator, compression flag, compression method, empty language tag, empty translated keyword
+    data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    data.extend_from_slice(text.as_bytes());
+    png_chunk(b"iTXt", &data)
+}
+
+/// insert chunks into a PNG right after IHDR, which is always the first chunk
+fn insert_png_chunks(mut image: Vec<u8>, chunks: &[u8]) -> Vec<u8> {
+    let ihdr_len = u32::from_be_bytes(image[PNG_SIGNATURE_LEN..PNG_SIGNATURE_LEN + 4].try_into().unwrap()) as usize;
+    let position = PNG_SIGNATURE_LEN + ihdr_len + 12;
+    image.splice(position..position, chunks.iter().copied());
+    image
+}
+
+/// insert a segment into a JPEG after its JFIF header if it has one, or straight after the start of image otherwise
+fn insert_jpeg_segment(mut image: Vec<u8>, segment: &[u8]) -> Vec<u8> {
+    let mut position = JPEG_SOI_LEN;
+    if image[position..position + 2] == JPEG_APP0 {
+        let app0_len = u16::from_be_bytes([image[position + 2], image[position + 3]]) as usize;
+        position += 2 + app0_len;
+    }
+    image.splice(position..position, segment.iter().copied());
+    image
+}
+
+/// overwrite the density of a JPEG's JFIF header, or add a JFIF header with that density if it doesn't have one
+fn set_jfif_density(mut image: Vec<u8>, dpi: u16) -> Vec<u8> {
+    let position = JPEG_SOI_LEN;
+    if image[position..position + 2] == JPEG_APP0 && image[position + 4..position + 9] == *JFIF_IDENTIFIER {
+        let units = position + JFIF_UNITS_OFFSET;
+        image[units] = JFIF_UNIT_DPI;
+        image[units + 1..units + 3].copy_from_slice(&dpi.to_be_bytes());
+        image[units + 3..units + 5].copy_from_slice(&dpi.to_be_bytes());
+        image
+    } else {
+        // version 1.01, then the density, then no thumbnail
+        let mut segment = JPEG_APP0.to_vec();
+        segment.extend_from_slice(&16u16.to_be_bytes());
+        segment.extend_from_slice(JFIF_IDENTIFIER);
+        segment.extend_from_slice(&[1, 1, JFIF_UNIT_DPI]);
+        segment.extend_from_slice(&dpi.to_be_bytes());
+        segment.extend_from_slice(&dpi.to_be_bytes());
+        segment.extend_from_slice(&[0, 0]);
+        image.splice(position..position, segment);
+        image
+    }
+}
+
+/// build a JPEG APP1 segment holding a minimal EXIF block with a single IFD of ASCII tags
+fn exif_segment(tags: &[(u16, &str)]) -> Vec<u8> {
+    // little-endian TIFF header, with IFD0 immediately after it
+    let mut tiff: Vec<u8> = vec![b'I', b'I', 42, 0, 8, 0, 0, 0];
+
+    // values go after the IFD: a 2 byte count, 12 bytes per entry, and a 4 byte next-IFD offset
+    let mut values: Vec<u8> = Vec::new();
+    let values_offset = 8 + 2 + 12 * tags.len() + 4;
+
+    tiff.extend_from_slice(&(tags.len() as u16).to_le_bytes());
+    for (tag, value) in tags {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        tiff.extend_from_slice(&tag.to_le_bytes());
+        tiff.extend_from_slice(&EXIF_TYPE_ASCII.to_le_bytes());
+        tiff.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        if value.len() <= 4 {
+            // small values are stored inline, padded out to 4 bytes
+            value.resize(4, 0);
+            tiff.extend_from_slice(&value);
+        } else {
+            tiff.extend_from_slice(&((values_offset + values.len()) as u32).to_le_bytes());
+            values.extend_from_slice(&value);
+        }
+    }
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    tiff.extend_from_slice(&values);
+
+    let mut segment = JPEG_APP1.to_vec();
+    segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(&tiff);
+    segment
+}
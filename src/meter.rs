@@ -0,0 +1,70 @@
+use std::net::IpAddr;
+
+use image::{DynamicImage, Rgba};
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::rect::Rect;
+
+use crate::iphash::ip_hash;
+use crate::shape::{fill_rect, fill_rect_to, RoundedRect, stroke_rect};
+
+/// a "X% match" style meter drawn on each frame of an advert
+pub struct Meter {
+    /// left of the meter, including its border
+    pub x: i32,
+    /// top of the meter, including its border
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    /// how full the meter is, from 0.0 to 1.0. If None, it's derived from the client's IP.
+    pub fill: Option<f32>,
+    pub fill_color: Rgba<u8>,
+    pub background_color: Rgba<u8>,
+    pub border_color: Rgba<u8>,
+    /// radius of the meter's corners in pixels, where 0 is square
+    pub corner_radius: f32,
+}
+
+impl Meter {
+    /// how full the meter should be for a client. IP-derived fills land between 50% and 100%, because nobody clicks on a
+    /// 3% match, and stay the same across requests (and releases) so reloading doesn't give the game away.
+    pub fn fill_for(&self, ip_hash_key: &[u8], ip: IpAddr) -> f32 {
+        self.fill.unwrap_or_else(|| 0.5 + (ip_hash(ip_hash_key, ip) % 501) as f32 / 1000.0)
+    }
+}
+
+/// draw a meter filled to `fill` with its top left offset by (0, y_offset), for drawing onto later frames
+pub fn draw_meter(image: &mut DynamicImage, meter: &Meter, fill: f32, y_offset: i32) {
+    let outer = RoundedRect::new(meter.x as f32, (meter.y + y_offset) as f32, meter.width as f32, meter.height as f32, meter.corner_radius);
+    fill_rect(image, &outer, meter.background_color);
+
+    // the fill goes inside the 1px border
+    let inner = outer.inset(1.0);
+    fill_rect_to(image, &inner, inner.x + inner.width * fill.clamp(0.0, 1.0), meter.fill_color);
+
+    stroke_rect(image, &outer, 1.0, meter.border_color);
+}
+
+/// a loading bar that fills up a little more on each frame of an animated advert
+pub struct ProgressBar {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    pub color: Rgba<u8>,
+    /// color of the unfilled part, if it's drawn at all
+    pub background_color: Option<Rgba<u8>>,
+}
+
+/// draw the bar as it should look on `frame` of `frames`, offset by (0, y_offset). It's full on the last frame.
+pub fn draw_progress_bar(image: &mut DynamicImage, bar: &ProgressBar, frame: i32, frames: i32, y_offset: i32) {
+    if bar.width == 0 || bar.height == 0 {
+        return;
+    }
+    if let Some(background_color) = bar.background_color {
+        draw_filled_rect_mut(image, Rect::at(bar.x, bar.y + y_offset).of_size(bar.width, bar.height), background_color);
+    }
+    let filled = (u64::from(bar.width) * (frame + 1) as u64 / frames.max(1) as u64) as u32;
+    if filled > 0 {
+        draw_filled_rect_mut(image, Rect::at(bar.x, bar.y + y_offset).of_size(filled.min(bar.width), bar.height), bar.color);
+    }
+}
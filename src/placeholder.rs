@@ -0,0 +1,27 @@
+use std::io::Cursor;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+use crate::flatten;
+
+/// width of placeholders in pixels. Their height follows the aspect ratio of the first frame.
+const PLACEHOLDER_WIDTH: u32 = 32;
+/// the placeholder gets scaled back up by the browser, so it's blurred to hide the blockiness that would bring out
+const PLACEHOLDER_BLUR_SIGMA: f32 = 1.5;
+const PLACEHOLDER_JPEG_QUALITY: u8 = 50;
+
+/// a tiny, blurry JPEG of the first frame of an image, for front-ends to show while the real thing loads. Text isn't
+/// drawn on it, so it's the same for everyone and can be made once up front.
+pub fn low_quality_placeholder(image: &DynamicImage, frame_height: u32) -> Vec<u8> {
+    let frame = image.crop_imm(0, 0, image.width(), frame_height.min(image.height()));
+    let height = (u64::from(PLACEHOLDER_WIDTH) * u64::from(frame.height()) / u64::from(frame.width().max(1))).max(1) as u32;
+    let small = flatten(&frame.resize_exact(PLACEHOLDER_WIDTH, height, FilterType::Triangle))
+        .blur(PLACEHOLDER_BLUR_SIGMA);
+
+    let mut buffer: Vec<u8> = Vec::new();
+    small.write_with_encoder(JpegEncoder::new_with_quality(&mut Cursor::new(&mut buffer), PLACEHOLDER_JPEG_QUALITY))
+        .expect("failed to encode placeholder");
+    buffer
+}
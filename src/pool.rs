@@ -0,0 +1,174 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+
+/// a stash of encode buffers. Encoding into a buffer that's already grown to the size of a typical output saves
+/// reallocating over and over as a fresh one grows. Finished buffers are handed out as [Bytes] without copying, and
+/// come back to the pool once the last handle to them is dropped, e.g. when the response has been sent and the render
+/// cache has let go of them.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+    capacity: usize,
+}
+
+impl BufferPool {
+    /// a pool holding up to `capacity` idle buffers. A capacity of 0 disables pooling.
+    pub fn new(capacity: usize) -> BufferPool {
+        BufferPool {
+            buffers: Arc::new(Mutex::new(Vec::new())),
+            capacity,
+        }
+    }
+
+    /// get an empty buffer, reusing an idle one if there is one
+    pub fn take(&self) -> Vec<u8> {
+        let mut buffer = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buffer.clear();
+        buffer
+    }
+
+    /// hand a buffer back for reuse. It's dropped if the pool is full.
+    pub fn give(&self, buffer: Vec<u8>) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.capacity {
+            buffers.push(buffer);
+        }
+    }
+
+    /// share a finished buffer's contents without copying them. The buffer is handed back for reuse once they're
+    /// dropped.
+    pub fn finish(&self, buffer: Vec<u8>) -> Bytes {
+        if self.capacity == 0 {
+            return Bytes::from(buffer);
+        }
+        Bytes::from_owner(Loaned { buffer, pool: self.clone() })
+    }
+}
+
+/// a finished buffer that goes back to its pool when dropped
+struct Loaned {
+    buffer: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl AsRef<[u8]> for Loaned {
+    fn as_ref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl Drop for Loaned {
+    fn drop(&mut self) {
+        self.pool.give(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use crate::advert::ImageOutput;
+    use crate::animation::Animation;
+    use crate::encode_image;
+
+    use super::*;
+
+    /// counts the bytes each thread asks to allocate, so a test can measure itself while others run alongside it
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count(bytes: usize) {
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + bytes));
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// bytes allocated on this thread while running `f`
+    fn allocated_by(f: impl FnOnce()) -> usize {
+        let before = ALLOCATED.with(Cell::get);
+        f();
+        ALLOCATED.with(Cell::get) - before
+    }
+
+    /// a busy 640x360 image, which encodes to a few hundred kilobytes like a typical advert
+    fn sample_image() -> DynamicImage {
+        RgbImage::from_fn(640, 360, |x, y| Rgb([(x * 7 + y) as u8, (x ^ y) as u8, (x * y / 13) as u8])).into()
+    }
+
+    /// encode the image `rounds` times the way a render does, dropping each result as a sent response would
+    fn encode_rounds(pool: &BufferPool, image: &DynamicImage, rounds: usize) -> usize {
+        let animation = Animation { frame_width: 640, frame_height: 360, frames: 1, frame_duration_ms: 100 };
+        let mut encoded_len = 0;
+        for _ in 0..rounds {
+            let (buffer, _) = encode_image(image, &ImageOutput::Png, &[], &animation, pool).unwrap();
+            let bytes = pool.finish(buffer);
+            encoded_len = bytes.len();
+        }
+        encoded_len
+    }
+
+    #[test]
+    fn finished_buffers_are_shared_without_copying_and_then_reused() {
+        let pool = BufferPool::new(1);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(b"an encoded advert");
+        let address = buffer.as_ptr();
+
+        let bytes = pool.finish(buffer);
+        assert_eq!(bytes.as_ptr(), address);
+        let shared = bytes.clone();
+        drop(bytes);
+        assert_eq!(pool.take().capacity(), 0, "buffer came back while it was still shared");
+        drop(shared);
+        let reused = pool.take();
+        assert_eq!(reused.as_ptr(), address);
+    }
+
+    #[test]
+    fn pooling_saves_allocating_output_buffers() {
+        const ROUNDS: usize = 20;
+        let image = sample_image();
+
+        // warm both up, so one-off allocations (e.g. the encoder's lookup tables) don't count against either
+        let unpooled = BufferPool::new(0);
+        let pooled = BufferPool::new(1);
+        encode_rounds(&unpooled, &image, 1);
+        encode_rounds(&pooled, &image, 1);
+
+        let mut encoded_len = 0;
+        let without_pool = allocated_by(|| encoded_len = encode_rounds(&unpooled, &image, ROUNDS));
+        let with_pool = allocated_by(|| {
+            encode_rounds(&pooled, &image, ROUNDS);
+        });
+        println!(
+            "{} renders of {} bytes each allocated {} bytes without the pool, and {} bytes with it ({} bytes per render saved)",
+            ROUNDS, encoded_len, without_pool, with_pool, (without_pool - with_pool) / ROUNDS,
+        );
+        // without the pool every render grows a fresh buffer to at least the size of its output, and with it none do
+        assert!(without_pool - with_pool >= ROUNDS * encoded_len);
+    }
+}
@@ -0,0 +1,45 @@
+use image::{DynamicImage, Rgba};
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::rect::Rect;
+use qrcode::{Color, QrCode};
+
+/// width of the blank border the QR spec asks for around a code, in modules
+const QUIET_ZONE: u32 = 4;
+
+const DARK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+const LIGHT: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// where a QR code goes in an advert, and what it says
+pub struct QrOverlay {
+    /// QR content, with placeholders to be filled in by [crate::text::fill_template]
+    pub content: String,
+    /// left of the QR code, including its quiet zone
+    pub x: i32,
+    /// top of the QR code, including its quiet zone
+    pub y: i32,
+    /// width and height of the QR code in pixels, including its quiet zone
+    pub size: u32,
+}
+
+/// draw a QR code encoding `content` into a `size`×`size` square with its top left at (x, y)
+pub fn draw_qr(image: &mut DynamicImage, content: &str, x: i32, y: i32, size: u32) -> Result<(), String> {
+    let code = QrCode::new(content)
+        .map_err(|e| format!("failed to encode QR code: {:?}", e))?;
+
+    // scale modules up by a whole number of pixels so they stay sharp, and center the result in the square
+    let modules = code.width() as u32;
+    let module_size = (size / (modules + 2 * QUIET_ZONE)).max(1);
+    let margin = (size.saturating_sub(modules * module_size) / 2) as i32;
+
+    draw_filled_rect_mut(image, Rect::at(x, y).of_size(size, size), LIGHT);
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color == Color::Dark {
+            let module_x = (i as u32 % modules * module_size) as i32;
+            let module_y = (i as u32 / modules * module_size) as i32;
+            let rect = Rect::at(x + margin + module_x, y + margin + module_y).of_size(module_size, module_size);
+            draw_filled_rect_mut(image, rect, DARK);
+        }
+    }
+
+    Ok(())
+}
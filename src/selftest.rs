@@ -0,0 +1,73 @@
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::config::{Config, config_warning};
+use crate::{DEFAULT_BROWSER, DEFAULT_OS, iso_string, layout_text, render_composite, render_location_to_image, text_style, Visitor};
+
+/// an everyday city name, which every advert should fit
+const SAMPLE_CITY: &str = "Springfield";
+/// about the longest name GeoIP could plausibly give a city. Adverts overflowing with this are reported, but not failed.
+const LONG_CITY: &str = "Llanfairpwllgwyngyllgogerychwyrndrobwllllantysiliogogogoch";
+
+/// one line of the self-test summary
+struct TestResult {
+    name: String,
+    city: &'static str,
+    outcome: String,
+}
+
+/// render every advert and composite for a sample city and a very long one before any traffic arrives. Failed
+/// renders, and text that overflows even with the sample city, are config warnings, so strict mode fails startup.
+pub fn self_test(config: &Config) {
+    let mut names: Vec<&String> = config.adverts.keys().chain(config.composites.keys()).collect();
+    names.sort();
+
+    let mut results = Vec::new();
+    let mut problems = Vec::new();
+    for name in names {
+        for city in [SAMPLE_CITY, LONG_CITY] {
+            let visitor = Visitor {
+                ip: IpAddr::from([0, 0, 0, 0]),
+                location: city.to_owned(),
+                browser: DEFAULT_BROWSER.to_owned(),
+                os: DEFAULT_OS.to_owned(),
+                country: None,
+            };
+
+            let start = Instant::now();
+            let (render, overflow) = match (config.adverts.get(name), config.composites.get(name)) {
+                (Some(advert), _) => {
+                    let layout = layout_text(advert, &visitor, &text_style(advert));
+                    let overflow = layout.x + layout.width - advert.image_width;
+                    (render_location_to_image(name, advert, &visitor, &config.encode_buffers).map(|(image, _)| image), overflow)
+                }
+                (None, Some(composite)) => (render_composite(composite, &visitor, &config.encode_buffers).map(|(image, _)| image), 0),
+                (None, None) => unreachable!("self-tested a name that isn't an advert or composite"),
+            };
+            let elapsed = start.elapsed().as_millis();
+
+            let failed = render.is_err();
+            let outcome = match render {
+                Ok(image) if overflow > 0 => format!("overflowed by {}px ({} bytes, {}ms)", overflow, image.len(), elapsed),
+                Ok(image) => format!("ok ({} bytes, {}ms)", image.len(), elapsed),
+                Err(e) => format!("failed: {}", e),
+            };
+            if failed || (city == SAMPLE_CITY && overflow > 0) {
+                problems.push(format!("self-test of \"{}\" with \"{}\" {}", name, city, outcome));
+            }
+            results.push(TestResult { name: name.clone(), city, outcome });
+        }
+    }
+
+    let name_width = results.iter().map(|result| result.name.len()).max().unwrap_or(0).max("advert".len());
+    let city_width = LONG_CITY.len();
+    println!("[{}] Self-test results:", iso_string());
+    println!("    {:name_width$}  {:city_width$}  result", "advert", "city");
+    for result in &results {
+        println!("    {:name_width$}  {:city_width$}  {}", result.name, result.city, result.outcome);
+    }
+
+    for problem in problems {
+        config_warning(&config.server, problem);
+    }
+}
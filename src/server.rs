@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::{Body, Request, Response, StatusCode, Uri, Version};
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_TYPE, HeaderValue, HOST, LOCATION, REFERER, USER_AGENT};
+use hyper::server::conn::Http;
+use hyper::service::{Service, service_fn};
+use tokio::net::TcpListener;
+use warp::{Filter, Rejection, Reply};
+use warp::host::Authority;
+
+use crate::config::{AccessLogFormat, ServerDefinition};
+use crate::iso_string;
+
+/// open connection counts, by client IP
+type ConnectionCounts = Arc<Mutex<HashMap<IpAddr, usize>>>;
+
+/// the address of the client that sent a request, attached to each request by [serve]
+#[derive(Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+/// what a request is currently doing. Handlers update it so that timeouts can say where the time went.
+#[derive(Clone)]
+pub struct RequestPhase(Arc<Mutex<&'static str>>);
+
+impl RequestPhase {
+    pub fn set(&self, phase: &'static str) {
+        *self.0.lock().unwrap() = phase;
+    }
+
+    fn get(&self) -> &'static str {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl Default for RequestPhase {
+    fn default() -> Self {
+        RequestPhase(Arc::new(Mutex::new("handling")))
+    }
+}
+
+/// extracts the phase tracker for the current request, which [serve] reports if the request times out
+pub fn request_phase() -> impl Filter<Extract=(RequestPhase, ), Error=Infallible> + Clone {
+    warp::ext::optional::<RequestPhase>()
+        .map(|phase: Option<RequestPhase>| phase.unwrap_or_default())
+}
+
+/// extracts the lowercased host name the client asked for, without any port
+pub fn host() -> impl Filter<Extract=(Option<String>, ), Error=Rejection> + Clone {
+    warp::host::optional()
+        .map(|authority: Option<Authority>| authority.map(|authority| authority.host().to_ascii_lowercase()))
+}
+
+/// the request headers that change how we respond to a client
+pub struct ClientHeaders {
+    pub user_agent: Option<String>,
+    /// whether the client asked for JSON, in which case errors are sent as JSON rather than plain text
+    pub accepts_json: bool,
+}
+
+/// extracts the headers that change how we respond to a client
+pub fn client_headers() -> impl Filter<Extract=(ClientHeaders, ), Error=Rejection> + Clone {
+    warp::header::optional::<String>("user-agent")
+        .and(warp::header::optional::<String>("accept"))
+        .map(|user_agent: Option<String>, accept: Option<String>| ClientHeaders {
+            user_agent,
+            accepts_json: accept.is_some_and(|accept| accept.contains("application/json")),
+        })
+}
+
+/// extracts the client's address. Use this instead of warp::filters::addr::remote, which doesn't work with [serve].
+pub fn remote() -> impl Filter<Extract=(Option<SocketAddr>, ), Error=Infallible> + Clone {
+    warp::ext::optional::<RemoteAddr>()
+        .map(|remote: Option<RemoteAddr>| remote.map(|RemoteAddr(addr)| addr))
+}
+
+/// the per-connection settings from [ServerDefinition] that each accept loop needs its own copy of
+#[derive(Clone, Copy)]
+struct ConnectionSettings {
+    max_connections_per_ip: Option<usize>,
+    request_timeout: Option<Duration>,
+    strict_paths: bool,
+    /// answer everything on this listener with a redirect to HTTPS instead of routing it
+    redirect_to_https: bool,
+    access_log: Option<AccessLogFormat>,
+}
+
+/// run a web server forever on every one of `addresses`. Unlike warp::serve, this lets us manage connections ourselves.
+pub async fn serve<F>(filter: F, addresses: &[SocketAddr], server: &ServerDefinition)
+where
+    F: Filter<Error=Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    // bind everything before accepting anything, so a bad address fails startup rather than leaving us half up
+    let mut listeners = Vec::with_capacity(addresses.len());
+    for &address in addresses {
+        let listener = TcpListener::bind(address).await
+            .unwrap_or_else(|e| panic!("failed to bind {}: {:?}", address, e));
+        listeners.push(listener);
+    }
+
+    let service = warp::service(filter);
+    // shared between listeners, so max_connections_per_ip is a limit across all of them
+    let connection_counts = ConnectionCounts::default();
+    let settings = ConnectionSettings {
+        max_connections_per_ip: server.max_connections_per_ip,
+        request_timeout: server.request_timeout_ms.map(Duration::from_millis),
+        strict_paths: server.strict_paths,
+        redirect_to_https: false,
+        access_log: server.access_log,
+    };
+
+    let mut http = Http::new();
+    // hyper would otherwise also accept cleartext HTTP/2 from clients that start with its preface
+    http.http1_only(!server.http2);
+    match server.keep_alive_timeout_secs {
+        Some(0) => {
+            http.http1_keep_alive(false);
+        }
+        // this only runs while hyper is waiting for a request's headers, so slow renders and uploads aren't cut off
+        Some(secs) => {
+            http.http1_header_read_timeout(Duration::from_secs(secs));
+        }
+        None => {}
+    }
+
+    let accept_loops: Vec<_> = addresses.iter().zip(listeners)
+        .map(|(address, listener)| {
+            let settings = ConnectionSettings {
+                redirect_to_https: server.redirect_to_https.contains(address),
+                ..settings
+            };
+            tokio::spawn(accept_connections(listener, service.clone(), connection_counts.clone(), http.clone(), settings))
+        })
+        .collect();
+    for accept_loop in accept_loops {
+        // these only finish by panicking, which should take the whole server down rather than quietly lose a port
+        if let Err(e) = accept_loop.await {
+            std::panic::resume_unwind(e.into_panic());
+        }
+    }
+}
+
+/// accept connections on one listener forever, serving each on its own task
+async fn accept_connections<S>(listener: TcpListener, service: S, connection_counts: ConnectionCounts, http: Http, settings: ConnectionSettings)
+where
+    S: Service<Request<Body>, Response=Response<Body>, Error=Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                // typically we're out of file descriptors, so give some connections a moment to close
+                eprintln!("[{}] failed to accept connection: {:?}", iso_string(), e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let guard = match settings.max_connections_per_ip {
+            Some(limit) => match ConnectionGuard::acquire(&connection_counts, remote_addr.ip(), limit) {
+                Some(guard) => Some(guard),
+                None => {
+                    eprintln!("[{}] refused connection from {}: too many open connections", iso_string(), remote_addr.ip());
+                    continue;
+                }
+            },
+            None => None,
+        };
+
+        let mut connection_service = service.clone();
+        let connection_service = service_fn(move |mut request: Request<Body>| {
+            // the access log wants the request as the client sent it, so grab it before any rewriting
+            let access = settings.access_log.map(|format| (format, AccessLogEntry::new(&request, remote_addr)));
+            if !settings.strict_paths {
+                normalize_path(&mut request);
+            }
+            let phase = RequestPhase::default();
+            let path = request.uri().path().to_owned();
+            request.extensions_mut().insert(RemoteAddr(remote_addr));
+            request.extensions_mut().insert(phase.clone());
+            // redirected requests never reach the routes at all
+            let response = match settings.redirect_to_https {
+                true => Err(https_redirect_response(&request)),
+                false => Ok(connection_service.call(request)),
+            };
+            async move {
+                let response = match response {
+                    Ok(response) => match settings.request_timeout {
+                        Some(limit) => match tokio::time::timeout(limit, response).await {
+                            Ok(response) => response?,
+                            Err(_) => {
+                                eprintln!("[{}] 504: {} timed out after {}ms while {}", iso_string(), path, limit.as_millis(), phase.get());
+                                timeout_response()
+                            }
+                        },
+                        None => response.await?,
+                    },
+                    Err(redirect) => redirect,
+                };
+                if let Some((format, entry)) = access {
+                    entry.log(format, &response);
+                }
+                Ok::<_, Infallible>(response)
+            }
+        });
+        let connection = http.serve_connection(stream, connection_service);
+        tokio::spawn(async move {
+            // errors here are almost always the client misbehaving or going away, which isn't our problem
+            let _ = connection.await;
+            drop(guard);
+        });
+    }
+}
+
+/// what the access log records about a request, taken before it's handled
+struct AccessLogEntry {
+    remote_addr: SocketAddr,
+    time: DateTime<Utc>,
+    method: String,
+    target: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl AccessLogEntry {
+    fn new(request: &Request<Body>, remote_addr: SocketAddr) -> AccessLogEntry {
+        let header = |name| request.headers().get(name)
+            .map(|value: &HeaderValue| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        AccessLogEntry {
+            remote_addr,
+            time: Utc::now(),
+            method: request.method().to_string(),
+            target: request.uri().path_and_query().map_or_else(|| request.uri().path().to_owned(), |target| target.to_string()),
+            version: request.version(),
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+
+    /// print a line for this request and the response it got
+    fn log(self, format: AccessLogFormat, response: &Response<Body>) {
+        let status = response.status().as_u16();
+        let bytes = response.body().size_hint().exact();
+        let ip = self.remote_addr.ip();
+        match format {
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                // everything the client controls is escaped, so it can't close a quoted field early and forge the rest
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {:?}\" {} {}",
+                    ip, self.time.format("%d/%b/%Y:%H:%M:%S %z"), escape_quoted(&self.method), escape_quoted(&self.target),
+                    self.version, status, bytes.map_or_else(|| "-".to_owned(), |bytes| bytes.to_string()),
+                );
+                if format == AccessLogFormat::Combined {
+                    let quoted = |value: Option<String>| value.map_or_else(|| "-".to_owned(), |value| escape_quoted(&value));
+                    line.push_str(&format!(" \"{}\" \"{}\"", quoted(self.referer), quoted(self.user_agent)));
+                }
+                println!("{}", line);
+            }
+            AccessLogFormat::Json => {
+                let line = serde_json::json!({
+                    "ip": ip.to_string(),
+                    "time": self.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    "method": self.method,
+                    "target": self.target,
+                    "protocol": format!("{:?}", self.version),
+                    "status": status,
+                    "bytes": bytes,
+                    "referer": self.referer,
+                    "user_agent": self.user_agent,
+                });
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+/// escape backslashes and double quotes, for a value written inside a quoted access log field
+fn escape_quoted(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// rewrite a request's path so that common variations of it match the same route: runs of slashes become one, and
+/// a trailing slash is dropped
+fn normalize_path(request: &mut Request<Body>) {
+    let path = request.uri().path();
+    if !path.contains("//") && (path == "/" || !path.ends_with('/')) {
+        return;
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    if let Some(query) = request.uri().query() {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+
+    let mut parts = request.uri().clone().into_parts();
+    match normalized.parse() {
+        Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+        Err(_) => return,
+    }
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *request.uri_mut() = uri;
+    }
+}
+
+/// a permanent redirect to the same URL over HTTPS on the default port, going by the Host header. Clients that don't
+/// send one can't be redirected anywhere sensible, so they get a 400.
+fn https_redirect_response(request: &Request<Body>) -> Response<Body> {
+    let host = request.headers().get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok());
+    let location = host.and_then(|host| {
+        let path_and_query = request.uri().path_and_query().map_or("/", |path_and_query| path_and_query.as_str());
+        HeaderValue::from_str(&format!("https://{}{}", host.host(), path_and_query)).ok()
+    });
+
+    let mut response = Response::new(Body::empty());
+    match location {
+        Some(location) => {
+            *response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+            response.headers_mut().insert(LOCATION, location);
+        }
+        None => {
+            *response.body_mut() = Body::from("missing or invalid Host header");
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        }
+    }
+    response
+}
+
+/// the response sent in place of one that took longer than request_timeout_ms
+fn timeout_response() -> Response<Body> {
+    let mut response = Response::new(Body::from("request timed out"));
+    *response.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
+
+/// counts as one open connection from an IP for as long as it's alive
+struct ConnectionGuard {
+    counts: ConnectionCounts,
+    ip: IpAddr,
+}
+
+impl ConnectionGuard {
+    /// count a new connection from `ip`, unless it already has `limit` open
+    fn acquire(counts: &ConnectionCounts, ip: IpAddr, limit: usize) -> Option<ConnectionGuard> {
+        let mut map = counts.lock().unwrap();
+        let count = map.entry(ip).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            counts: counts.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut map = self.counts.lock().unwrap();
+        if let Some(count) = map.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                map.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+
+use crate::text::blend;
+
+/// a rectangle with optionally rounded corners. Edges are anti-aliased, so fractional positions and sizes are fine.
+#[derive(Clone, Copy)]
+pub struct RoundedRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    /// radius of the corners in pixels, where 0 is square. Clamped to fit the rectangle.
+    pub radius: f32,
+}
+
+impl RoundedRect {
+    pub fn new(x: f32, y: f32, width: f32, height: f32, radius: f32) -> RoundedRect {
+        RoundedRect { x, y, width, height, radius }
+    }
+
+    /// the same shape shrunk by `by` pixels on every side, with correspondingly tighter corners
+    pub fn inset(&self, by: f32) -> RoundedRect {
+        RoundedRect {
+            x: self.x + by,
+            y: self.y + by,
+            width: (self.width - 2.0 * by).max(0.0),
+            height: (self.height - 2.0 * by).max(0.0),
+            radius: (self.radius - by).max(0.0),
+        }
+    }
+
+    /// signed distance from a point to the edge of the shape, negative inside
+    fn distance(&self, x: f32, y: f32) -> f32 {
+        let half_width = self.width / 2.0;
+        let half_height = self.height / 2.0;
+        let radius = self.radius.min(half_width).min(half_height);
+        let qx = (x - self.x - half_width).abs() - (half_width - radius);
+        let qy = (y - self.y - half_height).abs() - (half_height - radius);
+        qx.max(0.0).hypot(qy.max(0.0)) + qx.max(qy).min(0.0) - radius
+    }
+
+    /// roughly how much of the pixel at (x, y) the shape covers, from 0.0 to 1.0
+    fn coverage(&self, x: u32, y: u32) -> f32 {
+        if self.width <= 0.0 || self.height <= 0.0 {
+            return 0.0;
+        }
+        (0.5 - self.distance(x as f32 + 0.5, y as f32 + 0.5)).clamp(0.0, 1.0)
+    }
+}
+
+/// fill a shape with a color
+pub fn fill_rect(image: &mut DynamicImage, rect: &RoundedRect, color: Rgba<u8>) {
+    paint(image, rect, |_| color, |x, y| rect.coverage(x, y));
+}
+
+/// fill a shape with a color, but only the part of it left of `right`
+pub fn fill_rect_to(image: &mut DynamicImage, rect: &RoundedRect, right: f32, color: Rgba<u8>) {
+    paint(image, rect, |_| color, |x, y| rect.coverage(x, y) * (right - x as f32).clamp(0.0, 1.0));
+}
+
+/// draw the outline of a shape, `thickness` pixels wide and entirely inside it
+pub fn stroke_rect(image: &mut DynamicImage, rect: &RoundedRect, thickness: f32, color: Rgba<u8>) {
+    stroke_rect_gradient(image, rect, thickness, color, color);
+}
+
+/// draw the outline of a shape like [stroke_rect], but fading from `top` at its top edge to `bottom` at its bottom edge
+pub fn stroke_rect_gradient(image: &mut DynamicImage, rect: &RoundedRect, thickness: f32, top: Rgba<u8>, bottom: Rgba<u8>) {
+    let inner = rect.inset(thickness);
+    let color = |y: u32| {
+        let t = ((y as f32 + 0.5 - rect.y) / rect.height.max(1.0)).clamp(0.0, 1.0);
+        Rgba(std::array::from_fn(|i| (f32::from(top[i]) + (f32::from(bottom[i]) - f32::from(top[i])) * t).round() as u8))
+    };
+    paint(image, rect, color, |x, y| (rect.coverage(x, y) - inner.coverage(x, y)).max(0.0));
+}
+
+/// blend a color, which may vary by row, over every pixel in the bounds of a shape, as much as `coverage` says
+fn paint(image: &mut DynamicImage, rect: &RoundedRect, color: impl Fn(u32) -> Rgba<u8>, coverage: impl Fn(u32, u32) -> f32) {
+    let left = rect.x.floor().clamp(0.0, image.width() as f32) as u32;
+    let top = rect.y.floor().clamp(0.0, image.height() as f32) as u32;
+    let right = (rect.x + rect.width).ceil().clamp(0.0, image.width() as f32) as u32;
+    let bottom = (rect.y + rect.height).ceil().clamp(0.0, image.height() as f32) as u32;
+    for y in top..bottom {
+        for x in left..right {
+            let coverage = coverage(x, y);
+            if coverage > 0.0 {
+                let pixel = image.get_pixel(x, y);
+                image.put_pixel(x, y, blend(pixel, color(y), coverage));
+            }
+        }
+    }
+}
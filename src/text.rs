@@ -0,0 +1,355 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use ab_glyph::{Font, GlyphId, OutlinedGlyph, point, PxScale, ScaleFont};
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+
+use crate::{FONT, Visitor};
+
+/// everything about how a line of text looks, aside from its content and position
+pub struct TextStyle {
+    pub scale: PxScale,
+    pub color: Rgba<u8>,
+    /// round the baseline to a whole pixel so glyphs don't get smeared across two rows
+    pub snap_baseline: bool,
+    /// treat the y coordinate given to [draw_text] as the baseline rather than the top of the text
+    pub anchor_baseline: bool,
+    /// if set, each character is shifted up or down to follow this wave
+    pub wave: Option<Wave>,
+}
+
+/// a vertical sine wave running through a line of text, character by character
+pub struct Wave {
+    /// height of the wave's peaks, in pixels
+    pub amplitude: f32,
+    /// how far along the wave each character is from the last, in radians
+    pub frequency: f32,
+    /// where along the wave the first character is, in radians
+    pub phase: f32,
+}
+
+impl Wave {
+    /// vertical offset of the character at `index`
+    fn offset(&self, index: usize) -> f32 {
+        self.amplitude * (self.frequency * index as f32 + self.phase).sin()
+    }
+}
+
+/// fill in the placeholders of a user-provided template: `{city}`, `{browser}`, and `{os}`
+pub fn fill_template(template: &str, visitor: &Visitor) -> String {
+    template
+        .replace("{city}", &visitor.location)
+        .replace("{browser}", &visitor.browser)
+        .replace("{os}", &visitor.os)
+}
+
+/// find the characters in `text` that our font can't draw
+pub fn missing_glyphs(text: &str) -> Vec<char> {
+    let mut missing: Vec<char> = text.chars()
+        .filter(|c| !c.is_whitespace() && FONT.glyph_id(*c).0 == 0)
+        .collect();
+    missing.sort_unstable();
+    missing.dedup();
+    missing
+}
+
+/// get the width and height of a line of text
+pub fn text_size(style: &TextStyle, text: &str) -> (u32, u32) {
+    layout_glyphs(style, text, |_| {})
+}
+
+/// get the left, top, right, and bottom of the ink of a line of text, relative to where draw_text would be given to put
+/// it, or None if nothing would be drawn. Unlike text_size, this covers descenders and where the baseline falls.
+pub fn text_extent(style: &TextStyle, text: &str) -> Option<(i32, i32, i32, i32)> {
+    let mut extent: Option<(f32, f32, f32, f32)> = None;
+    layout_glyphs(style, text, |glyph| {
+        let bounds = glyph.px_bounds();
+        extent = Some(match extent {
+            Some((left, top, right, bottom)) => (left.min(bounds.min.x), top.min(bounds.min.y), right.max(bounds.max.x), bottom.max(bounds.max.y)),
+            None => (bounds.min.x, bounds.min.y, bounds.max.x, bounds.max.y),
+        });
+    });
+    extent.map(|(left, top, right, bottom)| (left.floor() as i32, top.floor() as i32, right.ceil() as i32, bottom.ceil() as i32))
+}
+
+/// draw a line of text onto an image, with the top left of the text at (x, y), or the left of its baseline if the style
+/// is anchored to the baseline
+pub fn draw_text(image: &mut DynamicImage, style: &TextStyle, x: i32, y: i32, text: &str) {
+    let image_width = image.width() as i32;
+    let image_height = image.height() as i32;
+
+    layout_glyphs(style, text, |glyph| {
+        let bounds = glyph.px_bounds();
+        glyph.draw(|glyph_x, glyph_y, coverage| {
+            let image_x = x + bounds.min.x as i32 + glyph_x as i32;
+            let image_y = y + bounds.min.y as i32 + glyph_y as i32;
+
+            if (0..image_width).contains(&image_x) && (0..image_height).contains(&image_y) {
+                let (image_x, image_y) = (image_x as u32, image_y as u32);
+                let pixel = image.get_pixel(image_x, image_y);
+                image.put_pixel(image_x, image_y, blend(pixel, style.color, coverage.clamp(0.0, 1.0)));
+            }
+        });
+    });
+}
+
+/// pick whichever of a dark and a light color will stand out against a region of an image, going by its mean luminance
+pub fn contrasting_color(image: &DynamicImage, x: i32, y: i32, width: u32, height: u32, dark: Rgba<u8>, light: Rgba<u8>) -> Rgba<u8> {
+    let left = x.clamp(0, image.width() as i32) as u32;
+    let top = y.clamp(0, image.height() as i32) as u32;
+    let right = (x + width as i32).clamp(0, image.width() as i32) as u32;
+    let bottom = (y + height as i32).clamp(0, image.height() as i32) as u32;
+
+    let mut total = 0.0f64;
+    let mut count = 0u64;
+    for pixel_y in top..bottom {
+        for pixel_x in left..right {
+            let pixel = image.get_pixel(pixel_x, pixel_y);
+            // Rec. 709 luma
+            total += 0.2126 * f64::from(pixel[0]) + 0.7152 * f64::from(pixel[1]) + 0.0722 * f64::from(pixel[2]);
+            count += 1;
+        }
+    }
+
+    if count > 0 && total / count as f64 > 127.5 {
+        dark
+    } else {
+        light
+    }
+}
+
+/// the most common color in an image, roughly: pixels are bucketed by the top 4 bits of each channel, and the average of
+/// the fullest bucket wins. Mostly transparent pixels are left out, since they aren't really part of the background.
+pub fn dominant_color(image: &DynamicImage) -> Rgba<u8> {
+    let mut buckets: HashMap<[u8; 3], (u64, [u64; 3])> = HashMap::new();
+    for (_, _, pixel) in image.pixels() {
+        if pixel[3] < 128 {
+            continue;
+        }
+        let (count, totals) = buckets.entry([pixel[0] >> 4, pixel[1] >> 4, pixel[2] >> 4]).or_default();
+        *count += 1;
+        for (total, channel) in totals.iter_mut().zip(&pixel.0[..3]) {
+            *total += u64::from(*channel);
+        }
+    }
+
+    // ties go to the lowest bucket, as HashMap iteration order would otherwise pick a different one each time
+    buckets.into_iter()
+        .max_by_key(|(bucket, (count, _))| (*count, Reverse(*bucket)))
+        .map_or(Rgba([0, 0, 0, 255]), |(_, (count, totals))| {
+            let [r, g, b] = totals.map(|total| (total / count) as u8);
+            Rgba([r, g, b, 255])
+        })
+}
+
+/// a text color for a background: its complement if that stands out enough, or else whichever of black and white
+/// stands out more
+pub fn palette_text_color(background: Rgba<u8>) -> Rgba<u8> {
+    /// WCAG's minimum contrast for normal text
+    const MIN_CONTRAST: f64 = 4.5;
+    let contrast = |a: Rgba<u8>, b: Rgba<u8>| {
+        let (a, b) = (relative_luminance(a), relative_luminance(b));
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    };
+
+    let complement = Rgba([255 - background[0], 255 - background[1], 255 - background[2], 255]);
+    if contrast(complement, background) >= MIN_CONTRAST {
+        return complement;
+    }
+    let (black, white) = (Rgba([0, 0, 0, 255]), Rgba([255, 255, 255, 255]));
+    if contrast(black, background) > contrast(white, background) { black } else { white }
+}
+
+/// WCAG contrast ratio between freshly drawn text and what was behind it, given the text's region of the image before
+/// and after drawing. Pixels that changed are taken to be text, and the background is the region as it was before.
+/// None if no pixels changed, e.g. if the text was drawn entirely out of bounds.
+pub fn contrast_ratio(before: &DynamicImage, after: &DynamicImage) -> Option<f64> {
+    let mut background = 0.0f64;
+    let mut text = 0.0f64;
+    let mut text_count = 0u64;
+    for ((_, _, old), (_, _, new)) in before.pixels().zip(after.pixels()) {
+        background += relative_luminance(old);
+        if old != new {
+            text += relative_luminance(new);
+            text_count += 1;
+        }
+    }
+    if text_count == 0 {
+        return None;
+    }
+
+    let background = background / (before.width() * before.height()) as f64;
+    let text = text / text_count as f64;
+    let (lighter, darker) = if text > background { (text, background) } else { (background, text) };
+    Some((lighter + 0.05) / (darker + 0.05))
+}
+
+/// WCAG relative luminance of an sRGB pixel, from 0.0 for black to 1.0 for white
+fn relative_luminance(pixel: Rgba<u8>) -> f64 {
+    let linear = |channel: u8| {
+        let channel = f64::from(channel) / 255.0;
+        if channel <= 0.03928 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(pixel[0]) + 0.7152 * linear(pixel[1]) + 0.0722 * linear(pixel[2])
+}
+
+/// position each glyph of a line of text relative to its top left, and return the text's width and height
+fn layout_glyphs(style: &TextStyle, text: &str, mut f: impl FnMut(OutlinedGlyph)) -> (u32, u32) {
+    let font = FONT.as_scaled(style.scale);
+    let baseline = if style.anchor_baseline {
+        0.0
+    } else if style.snap_baseline {
+        font.ascent().round()
+    } else {
+        font.ascent()
+    };
+
+    let mut caret = 0.0f32;
+    let mut height = 0.0f32;
+    let mut last: Option<GlyphId> = None;
+    for (index, c) in text.chars().enumerate() {
+        let glyph_id = font.glyph_id(c);
+        let offset = style.wave.as_ref().map_or(0.0, |wave| wave.offset(index));
+        let glyph = glyph_id.with_scale_and_position(style.scale, point(caret, baseline + offset));
+        caret += font.h_advance(glyph_id);
+
+        if let Some(outline) = font.outline_glyph(glyph) {
+            // kerning is applied exactly as imageproc's draw_text_mut did it, after the glyph and only between drawn
+            // ones, so existing adverts keep the layout they had before we drew text ourselves
+            if let Some(last) = last {
+                caret += font.kern(glyph_id, last);
+            }
+            last = Some(glyph_id);
+            height = height.max(outline.px_bounds().height());
+            f(outline);
+        }
+    }
+
+    (caret as u32, height as u32)
+}
+
+/// composite `color` over `pixel`, where coverage is how much of the pixel the glyph (or shape) covers.
+/// The color's own alpha is honored, so faint text is faint rather than cutting a hole in the image.
+pub fn blend(pixel: Rgba<u8>, color: Rgba<u8>, coverage: f32) -> Rgba<u8> {
+    let src_alpha = coverage * f32::from(color[3]) / 255.0;
+    let dst_alpha = f32::from(pixel[3]) / 255.0;
+    let out_alpha = src_alpha + dst_alpha * (1.0 - src_alpha);
+    if out_alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let mut out = pixel;
+    for (out, color) in out.0[..3].iter_mut().zip(&color.0[..3]) {
+        let mixed = f32::from(*color) * src_alpha + f32::from(*out) * dst_alpha * (1.0 - src_alpha);
+        *out = (mixed / out_alpha).round() as u8;
+    }
+    out[3] = (out_alpha * 255.0).round() as u8;
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+
+    const SCALE: f32 = 40.0;
+
+    fn style(anchor_baseline: bool) -> TextStyle {
+        TextStyle {
+            scale: PxScale::from(SCALE),
+            color: Rgba([0, 0, 0, 255]),
+            // a whole-pixel baseline, so the two placements differ by exactly the rounded ascent
+            snap_baseline: true,
+            anchor_baseline,
+            wave: None,
+        }
+    }
+
+    /// the first and last rows of an image with anything drawn in them
+    fn inked_rows(image: &DynamicImage) -> (u32, u32) {
+        let rows: Vec<u32> = image.pixels().filter(|(_, _, pixel)| pixel[3] > 0).map(|(_, y, _)| y).collect();
+        (*rows.iter().min().unwrap(), *rows.iter().max().unwrap())
+    }
+
+    /// the first pixel where two drawings of text onto blank canvases differ by more than rounding, if any
+    fn first_difference(ours: &DynamicImage, theirs: &DynamicImage) -> Option<(u32, u32)> {
+        ours.pixels().zip(theirs.pixels())
+            .find(|((_, _, a), (_, _, b))| a[3].abs_diff(b[3]) > 1)
+            .map(|((x, y, _), _)| (x, y))
+    }
+
+    #[test]
+    fn text_is_measured_and_drawn_like_imageproc() {
+        // imageproc drew our text before we laid it out ourselves, so existing configs must come out the same
+        for text in ["Singles in your area", "AVATAR WAVE Ty, To. LT", "Llanfairpwllgwyngyllgogerychwyrndrobwllllantysiliogogogoch"] {
+            for scale in [12.0, 24.0, 40.0, 71.3] {
+                let style = TextStyle { scale: PxScale::from(scale), snap_baseline: false, ..style(false) };
+                assert_eq!(text_size(&style, text), imageproc::drawing::text_size(style.scale, &*FONT, text), "size of {:?} at {}", text, scale);
+
+                let mut ours = DynamicImage::from(RgbaImage::new(2000, 120));
+                draw_text(&mut ours, &style, 5, 7, text);
+                let mut theirs = RgbaImage::new(2000, 120);
+                imageproc::drawing::draw_text_mut(&mut theirs, style.color, 5, 7, style.scale, &*FONT, text);
+                let difference = first_difference(&ours, &DynamicImage::from(theirs));
+                assert_eq!(difference, None, "placement of {:?} at {}", text, scale);
+            }
+        }
+    }
+
+    #[test]
+    fn top_anchored_text_hangs_below_y() {
+        let (_, top, _, bottom) = text_extent(&style(false), "Hg").unwrap();
+        assert!(top >= 0, "ink starts at {} above y", top);
+        assert!(bottom > top);
+    }
+
+    #[test]
+    fn baseline_anchored_text_sits_on_y() {
+        let (_, top, _, bottom) = text_extent(&style(true), "Hg").unwrap();
+        // the H stands above the baseline, and the g's descender hangs below it
+        assert!(top < 0, "ink starts at {}, not above the baseline", top);
+        assert!(bottom > 0, "ink ends at {}, not below the baseline", bottom);
+    }
+
+    #[test]
+    fn anchoring_to_the_baseline_moves_text_up_by_the_ascent() {
+        let ascent = FONT.as_scaled(PxScale::from(SCALE)).ascent().round() as i32;
+        let top_anchored = text_extent(&style(false), "Hg").unwrap();
+        let baseline_anchored = text_extent(&style(true), "Hg").unwrap();
+        assert_eq!(top_anchored.0, baseline_anchored.0);
+        assert_eq!(top_anchored.1 - baseline_anchored.1, ascent);
+        assert_eq!(top_anchored.3 - baseline_anchored.3, ascent);
+    }
+
+    #[test]
+    fn text_is_drawn_where_text_extent_says() {
+        for anchor_baseline in [false, true] {
+            let style = style(anchor_baseline);
+            let y = 50;
+            let mut image = DynamicImage::from(RgbaImage::new(200, 100));
+            draw_text(&mut image, &style, 10, y, "Hg");
+            let (_, top, _, bottom) = text_extent(&style, "Hg").unwrap();
+            let (first, last) = inked_rows(&image);
+            // px_bounds is fractional, so allow for a faint partial row either side
+            assert!((first as i32 - (y + top)).abs() <= 1, "anchor_baseline = {}: ink starts at row {}, expected {}", anchor_baseline, first, y + top);
+            assert!((last as i32 + 1 - (y + bottom)).abs() <= 1, "anchor_baseline = {}: ink ends at row {}, expected {}", anchor_baseline, last + 1, y + bottom);
+        }
+    }
+
+    #[test]
+    fn dominant_color_breaks_ties_the_same_way_every_time() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([200, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([0, 0, 200, 255]));
+        let image = DynamicImage::ImageRgba8(image);
+        // each call gets a freshly seeded HashMap, so an order-dependent pick would flip between these
+        for _ in 0..32 {
+            assert_eq!(dominant_color(&image), Rgba([0, 0, 200, 255]));
+        }
+    }
+}
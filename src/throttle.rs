@@ -0,0 +1,41 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+
+/// how many IPs we remember render times for. Beyond this, the least recently seen are forgotten.
+const TRACKED_IPS: NonZeroUsize = match NonZeroUsize::new(65536) {
+    Some(capacity) => capacity,
+    None => panic!("TRACKED_IPS must not be zero"),
+};
+
+/// enforces a minimum interval between renders for each client IP
+pub struct RenderThrottle {
+    interval: Duration,
+    last_render: Mutex<LruCache<IpAddr, Instant>>,
+}
+
+impl RenderThrottle {
+    pub fn new(interval: Duration) -> RenderThrottle {
+        RenderThrottle {
+            interval,
+            last_render: Mutex::new(LruCache::new(TRACKED_IPS)),
+        }
+    }
+
+    /// record a render for `ip` if it's allowed one, or return how much longer it has to wait if not
+    pub fn try_render(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut last_render = self.last_render.lock().unwrap();
+        if let Some(last) = last_render.get(&ip) {
+            let elapsed = now.duration_since(*last);
+            if elapsed < self.interval {
+                return Err(self.interval - elapsed);
+            }
+        }
+        last_render.put(ip, now);
+        Ok(())
+    }
+}